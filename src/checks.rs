@@ -0,0 +1,74 @@
+use crate::messages::Check;
+use hyper::HeaderMap;
+use regex::Regex;
+use std::time::Duration;
+
+// What a check gets to look at. The body is only kept when one of the spec's
+// checks needs it.
+pub struct Outcome<'a> {
+    pub status: u16,
+    pub headers: &'a HeaderMap,
+    pub body: Option<&'a [u8]>,
+    pub size: u64,
+    pub latency: Duration,
+}
+
+// A Check with its patterns compiled, built once per run.
+pub enum CompiledCheck {
+    Status(Vec<u16>),
+    StatusRange(u16, u16),
+    HeaderPresent(String),
+    HeaderEquals(String, String),
+    HeaderMatches(String, Regex),
+    BodyContains(String),
+    BodyMatches(Regex),
+    JsonEquals(String, serde_json::Value),
+    MaxSize(u64),
+    MaxLatency(Duration),
+    // A pattern that failed to compile; every evaluation fails.
+    Invalid,
+}
+
+impl CompiledCheck {
+    pub fn new(check: &Check) -> Result<CompiledCheck, regex::Error> {
+        Ok(match check {
+            Check::Status(codes) => CompiledCheck::Status(codes.clone()),
+            Check::StatusRange { min, max } => CompiledCheck::StatusRange(*min, *max),
+            Check::HeaderPresent(name) => CompiledCheck::HeaderPresent(name.clone()),
+            Check::HeaderEquals { name, value } => {
+                CompiledCheck::HeaderEquals(name.clone(), value.clone())
+            }
+            Check::HeaderMatches { name, pattern } => {
+                CompiledCheck::HeaderMatches(name.clone(), Regex::new(pattern)?)
+            }
+            Check::BodyContains(s) => CompiledCheck::BodyContains(s.clone()),
+            Check::BodyMatches(pattern) => CompiledCheck::BodyMatches(Regex::new(pattern)?),
+            Check::JsonEquals { pointer, value } => {
+                CompiledCheck::JsonEquals(pointer.clone(), value.clone())
+            }
+            Check::MaxSize(bytes) => CompiledCheck::MaxSize(*bytes),
+            Check::MaxLatencyMs(ms) => CompiledCheck::MaxLatency(Duration::from_millis(*ms)),
+        })
+    }
+
+    pub fn passes(&self, o: &Outcome) -> bool {
+        let header = |name: &str| o.headers.get(name).and_then(|v| v.to_str().ok());
+        let body = || String::from_utf8_lossy(o.body.unwrap_or_default());
+        match self {
+            CompiledCheck::Status(codes) => codes.contains(&o.status),
+            CompiledCheck::StatusRange(min, max) => (*min..=*max).contains(&o.status),
+            CompiledCheck::HeaderPresent(name) => o.headers.contains_key(name.as_str()),
+            CompiledCheck::HeaderEquals(name, value) => header(name) == Some(value.as_str()),
+            CompiledCheck::HeaderMatches(name, re) => header(name).is_some_and(|v| re.is_match(v)),
+            CompiledCheck::BodyContains(s) => body().contains(s.as_str()),
+            CompiledCheck::BodyMatches(re) => re.is_match(&body()),
+            CompiledCheck::JsonEquals(pointer, value) => o
+                .body
+                .and_then(|b| serde_json::from_slice::<serde_json::Value>(b).ok())
+                .is_some_and(|json| json.pointer(pointer) == Some(value)),
+            CompiledCheck::MaxSize(bytes) => o.size <= *bytes,
+            CompiledCheck::MaxLatency(max) => o.latency <= *max,
+            CompiledCheck::Invalid => false,
+        }
+    }
+}
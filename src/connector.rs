@@ -0,0 +1,179 @@
+use futures::FutureExt;
+use hyper::{service::Service, Uri};
+use hyper_rustls::MaybeHttpsStream;
+use rustls::ClientConfig;
+use slog::{warn, Logger};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{self, TcpStream},
+    time,
+};
+use tokio_rustls::TlsConnector;
+use webpki::DNSNameRef;
+
+use crate::stats::{as_micros, Stats};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Does the same job as hyper-rustls' HttpsConnector, but resolves, connects
+// and handshakes in separate steps so each phase of a new connection can be
+// timed. Pooled connections never come through here.
+#[derive(Clone)]
+pub struct Connector {
+    tls_config: Arc<ClientConfig>,
+    connect_timeout: Option<Duration>,
+    stats: Stats,
+}
+
+impl Connector {
+    pub fn new(logger: &Logger, stats: Stats) -> Connector {
+        let mut config = ClientConfig::new();
+        config.root_store = match rustls_native_certs::load_native_certs() {
+            Ok(store) => store,
+            Err((Some(store), e)) => {
+                warn!(logger, "Could not load all certificates: {}", e);
+                store
+            }
+            Err((None, e)) => {
+                warn!(logger, "Could not load native certificates: {}", e);
+                rustls::RootCertStore::empty()
+            }
+        };
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Connector {
+            tls_config: Arc::new(config),
+            connect_timeout: None,
+            stats,
+        }
+    }
+
+    // Bounds resolving, connecting and the TLS handshake together.
+    pub fn with_connect_timeout(mut self, timeout: Option<Duration>) -> Connector {
+        self.connect_timeout = timeout;
+        self
+    }
+}
+
+// Failures before a connection exists, kept typed so errors::classify can
+// tell them apart from the io errors of connecting and handshaking.
+#[derive(Debug)]
+pub enum ConnectError {
+    Dns { host: String, reason: String },
+    // Hosts that can't be used for SNI are refused before handshaking.
+    InvalidDnsName(String),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Dns { host, reason } => write!(f, "dns error: {}: {}", host, reason),
+            ConnectError::InvalidDnsName(host) => write!(f, "invalid dnsname: {}", host),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+fn dns_error(host: &str, e: impl fmt::Display) -> ConnectError {
+    ConnectError::Dns {
+        host: host.to_string(),
+        reason: e.to_string(),
+    }
+}
+
+async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>, ConnectError> {
+    let addrs: Vec<SocketAddr> = net::lookup_host((host, port))
+        .await
+        .map_err(|e| dns_error(host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(dns_error(host, "no addresses found"));
+    }
+    Ok(addrs)
+}
+
+// Tries each resolved address in turn and returns the last error if none of
+// them accept.
+async fn connect(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(s) => return Ok(s),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::other("no addresses")))
+}
+
+impl Service<Uri> for Connector {
+    type Response = MaybeHttpsStream<TcpStream>;
+    type Error = BoxError;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let tls_config = self.tls_config.clone();
+        let mut stats = self.stats.clone();
+        stats.record_connect_attempt();
+        let connecting = establish(dst, tls_config, stats);
+        match self.connect_timeout {
+            Some(timeout) => async move {
+                match time::timeout(timeout, connecting).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out").into())
+                    }
+                }
+            }
+            .boxed(),
+            None => connecting.boxed(),
+        }
+    }
+}
+
+async fn establish(
+    dst: Uri,
+    tls_config: Arc<ClientConfig>,
+    mut stats: Stats,
+) -> Result<MaybeHttpsStream<TcpStream>, BoxError> {
+    let is_https = dst.scheme_str() == Some("https");
+    let host = dst
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = dst.port_u16().unwrap_or(if is_https { 443 } else { 80 });
+
+    let started = Instant::now();
+    let addrs = resolve(&host, port).await?;
+    let resolved = Instant::now();
+    let tcp = connect(&addrs).await?;
+    let connected = Instant::now();
+    let dns_us = as_micros(resolved - started);
+    let connect_us = as_micros(connected - resolved);
+    if !is_https {
+        stats.record_connection(dns_us, connect_us, None);
+        return Ok(MaybeHttpsStream::Http(tcp));
+    }
+
+    let dnsname = DNSNameRef::try_from_ascii_str(&host)
+        .map_err(|_| ConnectError::InvalidDnsName(host.clone()))?;
+    let tls = TlsConnector::from(tls_config).connect(dnsname, tcp).await?;
+    let tls_us = as_micros(connected.elapsed());
+    stats.record_connection(dns_us, connect_us, Some(tls_us));
+    Ok(MaybeHttpsStream::Https(tls))
+}
@@ -0,0 +1,330 @@
+use hyper::{header, HeaderMap};
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+#[derive(Debug, Clone)]
+struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    // Set without a Domain attribute, so only sent back to the exact host.
+    host_only: bool,
+    path: String,
+    expires: Option<SystemTime>,
+    secure: bool,
+}
+
+impl Cookie {
+    fn expired(&self, now: SystemTime) -> bool {
+        self.expires.map(|e| e <= now).unwrap_or(false)
+    }
+
+    fn matches(&self, url: &Url, now: SystemTime) -> bool {
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_match(&host, &self.domain)
+        };
+        domain_ok
+            && path_match(url.path(), &self.path)
+            && (!self.secure || url.scheme() == "https")
+            && !self.expired(now)
+    }
+}
+
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+fn path_match(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || (request_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || request_path[cookie_path.len()..].starts_with('/')))
+}
+
+// The directory of the request path, used when Set-Cookie has no Path.
+fn default_path(url: &Url) -> String {
+    let path = url.path();
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+// A minimal RFC 6265 cookie store: Domain, Path, Expires, Max-Age and Secure
+// are honoured, everything else is ignored.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        CookieJar::default()
+    }
+
+    pub fn clear(&mut self) {
+        self.cookies.clear();
+    }
+
+    // The value for a Cookie request header, if any cookies apply. Longer
+    // paths go first.
+    pub fn header_for(&self, url: &Url) -> Option<String> {
+        let now = SystemTime::now();
+        let mut matching: Vec<&Cookie> = self
+            .cookies
+            .iter()
+            .filter(|c| c.matches(url, now))
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        matching.sort_by_key(|c| std::cmp::Reverse(c.path.len()));
+        Some(
+            matching
+                .iter()
+                .map(|c| format!("{}={}", c.name, c.value))
+                .collect::<Vec<_>>()
+                .join("; "),
+        )
+    }
+
+    pub fn store(&mut self, url: &Url, headers: &HeaderMap) {
+        let now = SystemTime::now();
+        for value in headers.get_all(header::SET_COOKIE) {
+            if let Some(cookie) = value.to_str().ok().and_then(|v| parse(v, url, now)) {
+                self.cookies.retain(|c| {
+                    !(c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path)
+                });
+                // An expiry in the past is how servers delete a cookie.
+                if !cookie.expired(now) {
+                    self.cookies.push(cookie);
+                }
+            }
+        }
+        self.cookies.retain(|c| !c.expired(now));
+    }
+}
+
+fn parse(header: &str, url: &Url, now: SystemTime) -> Option<Cookie> {
+    let mut parts = header.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    let host = url.host_str()?.to_ascii_lowercase();
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: value.trim().to_string(),
+        domain: host.clone(),
+        host_only: true,
+        path: default_path(url),
+        expires: None,
+        secure: false,
+    };
+    let mut max_age = None;
+    for attr in parts {
+        let (key, val) = match attr.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => (attr.trim(), ""),
+        };
+        match key.to_ascii_lowercase().as_str() {
+            "domain" if !val.is_empty() => {
+                let domain = val.trim_start_matches('.').to_ascii_lowercase();
+                // Refuse cookies for domains the response didn't come from.
+                if !domain_match(&host, &domain) {
+                    return None;
+                }
+                cookie.domain = domain;
+                cookie.host_only = false;
+            }
+            "path" if val.starts_with('/') => cookie.path = val.to_string(),
+            "expires" => {
+                if let Ok(t) = httpdate::parse_http_date(val) {
+                    cookie.expires = Some(t);
+                }
+            }
+            "max-age" => {
+                if let Ok(secs) = val.parse::<i64>() {
+                    max_age = Some(secs);
+                }
+            }
+            "secure" => cookie.secure = true,
+            _ => {}
+        }
+    }
+    // Max-Age wins over Expires. One too large to represent never expires.
+    if let Some(secs) = max_age {
+        cookie.expires = if secs <= 0 {
+            Some(SystemTime::UNIX_EPOCH)
+        } else {
+            now.checked_add(Duration::from_secs(secs as u64))
+        };
+    }
+    Some(cookie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    fn set_cookie(jar: &mut CookieJar, from: &str, values: &[&str]) {
+        let mut headers = HeaderMap::new();
+        for v in values {
+            headers.append(header::SET_COOKIE, HeaderValue::from_str(v).unwrap());
+        }
+        jar.store(&url(from), &headers);
+    }
+
+    #[test]
+    fn host_only_cookies_stay_on_their_host() {
+        let mut jar = CookieJar::new();
+        set_cookie(&mut jar, "http://example.com/", &["a=1"]);
+        assert_eq!(
+            jar.header_for(&url("http://example.com/x")).as_deref(),
+            Some("a=1")
+        );
+        assert_eq!(jar.header_for(&url("http://www.example.com/x")), None);
+    }
+
+    #[test]
+    fn domain_cookies_reach_subdomains() {
+        let mut jar = CookieJar::new();
+        set_cookie(
+            &mut jar,
+            "http://www.example.com/",
+            &["a=1; Domain=.example.com"],
+        );
+        assert_eq!(
+            jar.header_for(&url("http://example.com/")).as_deref(),
+            Some("a=1")
+        );
+        assert_eq!(
+            jar.header_for(&url("http://api.example.com/")).as_deref(),
+            Some("a=1")
+        );
+        assert_eq!(jar.header_for(&url("http://notexample.com/")), None);
+    }
+
+    #[test]
+    fn foreign_domains_are_refused() {
+        let mut jar = CookieJar::new();
+        set_cookie(&mut jar, "http://example.com/", &["a=1; Domain=other.com"]);
+        set_cookie(
+            &mut jar,
+            "http://example.com/",
+            &["b=2; Domain=www.example.com"],
+        );
+        assert_eq!(jar.header_for(&url("http://other.com/")), None);
+        assert_eq!(jar.header_for(&url("http://www.example.com/")), None);
+    }
+
+    #[test]
+    fn default_path_is_the_request_directory() {
+        assert_eq!(default_path(&url("http://example.com")), "/");
+        assert_eq!(default_path(&url("http://example.com/login")), "/");
+        assert_eq!(default_path(&url("http://example.com/app/login")), "/app");
+        assert_eq!(default_path(&url("http://example.com/app/v1/")), "/app/v1");
+
+        let mut jar = CookieJar::new();
+        set_cookie(&mut jar, "http://example.com/app/login", &["a=1"]);
+        assert_eq!(
+            jar.header_for(&url("http://example.com/app/home"))
+                .as_deref(),
+            Some("a=1")
+        );
+        assert_eq!(
+            jar.header_for(&url("http://example.com/app")).as_deref(),
+            Some("a=1")
+        );
+        assert_eq!(jar.header_for(&url("http://example.com/apple")), None);
+        assert_eq!(jar.header_for(&url("http://example.com/")), None);
+    }
+
+    #[test]
+    fn longer_paths_go_first() {
+        let mut jar = CookieJar::new();
+        set_cookie(
+            &mut jar,
+            "http://example.com/",
+            &["a=1; Path=/", "b=2; Path=/app"],
+        );
+        assert_eq!(
+            jar.header_for(&url("http://example.com/app/x")).as_deref(),
+            Some("b=2; a=1")
+        );
+    }
+
+    #[test]
+    fn max_age_wins_over_expires() {
+        let now = SystemTime::now();
+        let u = url("http://example.com/");
+        let c = parse(
+            "a=1; Expires=Thu, 01 Jan 1970 00:00:01 GMT; Max-Age=60",
+            &u,
+            now,
+        )
+        .unwrap();
+        assert_eq!(c.expires, Some(now + Duration::from_secs(60)));
+        let c = parse(
+            "a=1; Max-Age=60; Expires=Thu, 01 Jan 1970 00:00:01 GMT",
+            &u,
+            now,
+        )
+        .unwrap();
+        assert_eq!(c.expires, Some(now + Duration::from_secs(60)));
+        let c = parse(
+            "a=1; Expires=Wed, 21 Oct 2099 07:28:00 GMT; Max-Age=0",
+            &u,
+            now,
+        )
+        .unwrap();
+        assert!(c.expired(now));
+    }
+
+    #[test]
+    fn huge_max_age_never_expires() {
+        let max = format!("a=1; Max-Age={}", i64::MAX);
+        let mut jar = CookieJar::new();
+        set_cookie(&mut jar, "http://example.com/", &[&max]);
+        assert_eq!(
+            jar.header_for(&url("http://example.com/")).as_deref(),
+            Some("a=1")
+        );
+    }
+
+    #[test]
+    fn past_expiry_deletes_the_cookie() {
+        let mut jar = CookieJar::new();
+        set_cookie(&mut jar, "http://example.com/", &["a=1", "b=2"]);
+        set_cookie(
+            &mut jar,
+            "http://example.com/",
+            &["a=gone; Expires=Thu, 01 Jan 1970 00:00:01 GMT"],
+        );
+        assert_eq!(
+            jar.header_for(&url("http://example.com/")).as_deref(),
+            Some("b=2")
+        );
+        set_cookie(&mut jar, "http://example.com/", &["b=gone; Max-Age=0"]);
+        assert_eq!(jar.header_for(&url("http://example.com/")), None);
+    }
+
+    #[test]
+    fn secure_cookies_need_https() {
+        let mut jar = CookieJar::new();
+        set_cookie(&mut jar, "https://example.com/", &["a=1; Secure"]);
+        assert_eq!(jar.header_for(&url("http://example.com/")), None);
+        assert_eq!(
+            jar.header_for(&url("https://example.com/")).as_deref(),
+            Some("a=1")
+        );
+    }
+}
@@ -190,6 +190,24 @@ async fn handle_connection(
                 }
             }
             CoordinatorResult::Broadcast(c) => {
+                let c = match c {
+                    messages::Command::Start(mut spec) => {
+                        match state.collector.shard(&spec, id) {
+                            Some(shard) => spec.shard = shard,
+                            None => {
+                                info!(logger, "Joined after run {} started", spec.run_id);
+                                continue;
+                            }
+                        }
+                        // Partitioned against the shards fixed at run start, so
+                        // rows handed out here never overlap another worker's.
+                        for f in spec.feeders.iter_mut() {
+                            f.partition(spec.shard);
+                        }
+                        messages::Command::Start(spec)
+                    }
+                    c => c,
+                };
                 let m = c.as_message()?;
                 let _ = tx.send(m).await;
             }
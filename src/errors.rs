@@ -0,0 +1,66 @@
+use crate::{connector::ConnectError, messages::ErrorKind};
+use std::io;
+
+// Buckets a request failure by walking its cause chain, deciding by the type
+// of each cause.
+pub fn classify(e: &anyhow::Error) -> ErrorKind {
+    for cause in e.chain() {
+        if cause.is::<url::ParseError>() || cause.is::<hyper::http::uri::InvalidUri>() {
+            return ErrorKind::InvalidUrl;
+        }
+        // Building a request fails for bad header values as often as bad urls,
+        // e.g. a feeder cell with a newline in it.
+        if let Some(h) = cause.downcast_ref::<hyper::http::Error>() {
+            let inner = h.get_ref();
+            if inner.is::<hyper::http::uri::InvalidUri>()
+                || inner.is::<hyper::http::uri::InvalidUriParts>()
+            {
+                return ErrorKind::InvalidUrl;
+            }
+            return ErrorKind::Other;
+        }
+        match cause.downcast_ref::<ConnectError>() {
+            Some(ConnectError::Dns { .. }) => return ErrorKind::Dns,
+            Some(ConnectError::InvalidDnsName(_)) => return ErrorKind::Tls,
+            None => {}
+        }
+        if let Some(io) = cause.downcast_ref::<io::Error>() {
+            match io_kind(io) {
+                io::ErrorKind::ConnectionRefused => return ErrorKind::ConnectionRefused,
+                io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof => return ErrorKind::ConnectionReset,
+                io::ErrorKind::TimedOut => return ErrorKind::Timeout,
+                // tokio-rustls surfaces handshake and certificate failures as
+                // InvalidData.
+                io::ErrorKind::InvalidData => return ErrorKind::Tls,
+                _ => {}
+            }
+        }
+        if let Some(h) = cause.downcast_ref::<hyper::Error>() {
+            if h.is_timeout() {
+                return ErrorKind::Timeout;
+            }
+            if h.is_parse() {
+                return ErrorKind::Protocol;
+            }
+            if h.is_incomplete_message() || h.is_closed() {
+                return ErrorKind::ConnectionReset;
+            }
+        }
+    }
+    ErrorKind::Other
+}
+
+// io::Error::source skips over a wrapped io::Error, so nested kinds have to be
+// dug out by hand.
+fn io_kind(e: &io::Error) -> io::ErrorKind {
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<io::Error>())
+    {
+        Some(inner) if e.kind() == io::ErrorKind::Other => io_kind(inner),
+        _ => e.kind(),
+    }
+}
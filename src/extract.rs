@@ -0,0 +1,53 @@
+use crate::checks::Outcome;
+use crate::messages::{ExtractSource, Extractor};
+use regex::Regex;
+
+enum Source {
+    JsonPointer(String),
+    Regex(Regex),
+    Header(String),
+}
+
+pub struct CompiledExtractor {
+    pub name: String,
+    source: Source,
+}
+
+impl CompiledExtractor {
+    pub fn new(e: &Extractor) -> Result<CompiledExtractor, regex::Error> {
+        let source = match e.source {
+            ExtractSource::JsonPointer(ref p) => Source::JsonPointer(p.clone()),
+            ExtractSource::Regex(ref pattern) => Source::Regex(Regex::new(pattern)?),
+            ExtractSource::Header(ref h) => Source::Header(h.clone()),
+        };
+        Ok(CompiledExtractor {
+            name: e.name.clone(),
+            source,
+        })
+    }
+
+    // JSON strings are taken as-is, anything else as its JSON text.
+    pub fn extract(&self, o: &Outcome) -> Option<String> {
+        match self.source {
+            Source::JsonPointer(ref p) => {
+                let json: serde_json::Value = serde_json::from_slice(o.body?).ok()?;
+                match json.pointer(p)? {
+                    serde_json::Value::String(s) => Some(s.clone()),
+                    v => Some(v.to_string()),
+                }
+            }
+            Source::Regex(ref re) => {
+                let body = String::from_utf8_lossy(o.body?);
+                let caps = re.captures(&body)?;
+                caps.get(1)
+                    .or_else(|| caps.get(0))
+                    .map(|m| m.as_str().to_string())
+            }
+            Source::Header(ref h) => o
+                .headers
+                .get(h.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(String::from),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::messages::{FeedOrder, Feeder};
+use anyhow::{anyhow, bail, Result};
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+type Rows = (Vec<String>, Vec<Vec<String>>);
+
+// The first record names the columns.
+pub fn parse_csv(data: &str) -> Result<Rows> {
+    let mut reader = csv::Reader::from_reader(data.as_bytes());
+    let columns = reader.headers()?.iter().map(String::from).collect();
+    let rows = reader
+        .records()
+        .map(|r| Ok(r?.iter().map(String::from).collect()))
+        .collect::<Result<_>>()?;
+    Ok((columns, rows))
+}
+
+// One JSON object per line. Columns are the keys in the order they are first
+// seen; non-string values are kept as JSON and missing keys are empty.
+pub fn parse_jsonl(data: &str) -> Result<Rows> {
+    let mut columns: Vec<String> = Vec::new();
+    let mut objects = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: serde_json::Value =
+            serde_json::from_str(line).map_err(|e| anyhow!("line {}: {}", i + 1, e))?;
+        let object = match value {
+            serde_json::Value::Object(o) => o,
+            _ => bail!("line {}: expected an object", i + 1),
+        };
+        for key in object.keys() {
+            if !columns.contains(key) {
+                columns.push(key.clone());
+            }
+        }
+        objects.push(object);
+    }
+    let rows = objects
+        .iter()
+        .map(|o| {
+            columns
+                .iter()
+                .map(|c| match o.get(c) {
+                    Some(serde_json::Value::String(s)) => s.clone(),
+                    Some(v) => v.to_string(),
+                    None => String::new(),
+                })
+                .collect()
+        })
+        .collect();
+    Ok((columns, rows))
+}
+
+// Hands out rows of a feeder to the requests of a run.
+pub struct FeederState {
+    order: FeedOrder,
+    rows: Vec<Vec<String>>,
+    next: AtomicUsize,
+}
+
+impl FeederState {
+    pub fn new(feeder: &Feeder) -> FeederState {
+        FeederState {
+            order: feeder.order,
+            rows: feeder.rows.clone(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    // None once a sequential feeder has used up its rows.
+    pub fn take(&self) -> Option<usize> {
+        if self.rows.is_empty() {
+            return None;
+        }
+        match self.order {
+            FeedOrder::Sequential => {
+                let i = self.next.fetch_add(1, Ordering::AcqRel);
+                if i < self.rows.len() {
+                    Some(i)
+                } else {
+                    None
+                }
+            }
+            FeedOrder::Circular => Some(self.next.fetch_add(1, Ordering::AcqRel) % self.rows.len()),
+            FeedOrder::Random => Some(rand::thread_rng().gen_range(0..self.rows.len())),
+        }
+    }
+
+    pub fn value(&self, row: usize, column: usize) -> &str {
+        self.rows[row].get(column).map(String::as_str).unwrap_or("")
+    }
+}
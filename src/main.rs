@@ -3,12 +3,23 @@ use anyhow::Result;
 use slog::{debug, error, o, Drain, Logger};
 use std::env;
 
+mod checks;
+mod connector;
+mod cookies;
 mod coordinator;
+mod errors;
+mod extract;
+mod feeder;
 mod messages;
+mod metrics;
+mod pacing;
+mod profile;
 mod static_assets;
 mod stats;
+mod template;
 mod webserver;
 mod worker;
+mod worker_server;
 
 fn root_logger() -> (Logger, slog_async::AsyncGuard) {
     let decorator = slog_term::TermDecorator::new().build();
@@ -22,7 +33,8 @@ fn main() -> Result<()> {
     let (log, _guard) = root_logger();
     debug!(log, "Logger initialized");
     let res = if let Some(addr) = env::args().nth(1) {
-        worker::run_forever(log.new(o!("type" => "worker")), addr)
+        let listen = env::args().nth(2);
+        worker::run_forever(log.new(o!("type" => "worker")), addr, listen)
     } else {
         let addr = "0.0.0.0:5556".to_string();
         let web_addr = "0.0.0.0:5555".to_string();
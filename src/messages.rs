@@ -1,15 +1,28 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    time::Duration,
+};
 use tungstenite::protocol::Message;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum AttackStrategy {
     Random,
     InOrder,
+    // Like InOrder, but the steps of an iteration share template state and
+    // can pass extracted values along.
+    Scenario,
+    // Picks specs at random in proportion to their weights.
+    Weighted,
+    // Cycles through the specs in order, one request per iteration, shared
+    // across all of a worker's tasks.
+    RoundRobin,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum RequestMethod {
     GET,
@@ -23,7 +36,7 @@ pub enum RequestMethod {
     TRACE,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum HttpVersion {
     Http11,
     Http2,
@@ -58,8 +71,86 @@ impl From<RequestMethod> for hyper::Method {
     }
 }
 
+// Assertions evaluated against every response a spec gets back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Check {
+    Status(Vec<u16>),
+    StatusRange {
+        min: u16,
+        max: u16,
+    },
+    HeaderPresent(String),
+    HeaderEquals {
+        name: String,
+        value: String,
+    },
+    HeaderMatches {
+        name: String,
+        pattern: String,
+    },
+    BodyContains(String),
+    BodyMatches(String),
+    JsonEquals {
+        pointer: String,
+        value: serde_json::Value,
+    },
+    MaxSize(u64),
+    MaxLatencyMs(u64),
+}
+
+impl Check {
+    pub fn needs_body(&self) -> bool {
+        matches!(
+            self,
+            Check::BodyContains(_) | Check::BodyMatches(_) | Check::JsonEquals { .. }
+        )
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Status(codes) => write!(f, "status in {:?}", codes),
+            Check::StatusRange { min, max } => write!(f, "status in {}..={}", min, max),
+            Check::HeaderPresent(name) => write!(f, "header {} present", name),
+            Check::HeaderEquals { name, value } => write!(f, "header {} == {:?}", name, value),
+            Check::HeaderMatches { name, pattern } => {
+                write!(f, "header {} matches /{}/", name, pattern)
+            }
+            Check::BodyContains(s) => write!(f, "body contains {:?}", s),
+            Check::BodyMatches(pattern) => write!(f, "body matches /{}/", pattern),
+            Check::JsonEquals { pointer, value } => write!(f, "json {} == {}", pointer, value),
+            Check::MaxSize(bytes) => write!(f, "size <= {} bytes", bytes),
+            Check::MaxLatencyMs(ms) => write!(f, "latency <= {}ms", ms),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ExtractSource {
+    JsonPointer(String),
+    // The first capture group if the pattern has one, else the whole match.
+    Regex(String),
+    Header(String),
+}
+
+// Captures a value from a scenario step's response into a variable that
+// later steps can use as `{{name}}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Extractor {
+    pub name: String,
+    pub source: ExtractSource,
+}
+
+impl Extractor {
+    pub fn needs_body(&self) -> bool {
+        !matches!(self.source, ExtractSource::Header(_))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RequestSpec {
+    pub name: Option<String>,
     pub version: HttpVersion,
     pub method: RequestMethod,
     pub url: String,
@@ -67,30 +158,191 @@ pub struct RequestSpec {
     pub headers: HashMap<String, String>,
     pub random_querystring: Option<String>,
     pub random_header: Option<String>,
+    pub connect_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub checks: Vec<Check>,
+    pub extract: Vec<Extractor>,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Interpolation {
+    Linear,
+    Step,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stage {
+    pub duration: Duration,
+    pub target: f64,
+    pub interpolation: Interpolation,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ActiveStage {
+    pub index: usize,
+    pub target: f64,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    pub index: u32,
+    pub count: u32,
+}
+
+impl Shard {
+    pub fn single() -> Shard {
+        Shard { index: 0, count: 1 }
+    }
+
+    pub fn split(&self, total: u64) -> u64 {
+        let count = u64::from(self.count.max(1));
+        let index = u64::from(self.index);
+        total / count + if index < total % count { 1 } else { 0 }
+    }
+
+    // An even share of a rate meant for the whole run.
+    pub fn share(&self, total: f64) -> f64 {
+        total / f64::from(self.count.max(1))
+    }
+}
+
+// Connection pool settings shared by every task in a run. Unset values fall
+// back to hyper's defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Pool {
+    pub max_idle_per_host: Option<usize>,
+    pub idle_timeout: Option<Duration>,
+    pub new_connection_per_request: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum FeedOrder {
+    // Each row once; the run ends when they run out.
+    Sequential,
+    Random,
+    Circular,
+}
+
+// A named table of rows whose columns requests can refer to as
+// `{{name.column}}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Feeder {
+    pub name: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+    pub order: FeedOrder,
+    pub partition: bool,
+}
+
+impl Feeder {
+    // Keeps only this shard's rows when the feeder is partitioned, so workers
+    // never share a row.
+    pub fn partition(&mut self, shard: Shard) {
+        if !self.partition {
+            return;
+        }
+        let count = shard.count.max(1) as usize;
+        let index = shard.index as usize;
+        self.rows = self
+            .rows
+            .drain(..)
+            .enumerate()
+            .filter(|(i, _)| i % count == index)
+            .map(|(_, r)| r)
+            .collect();
+    }
+}
+
+// A pause drawn from a distribution, in milliseconds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ThinkTime {
+    Constant(u64),
+    Uniform { min_ms: u64, max_ms: u64 },
+    Normal { mean_ms: u64, stdev_ms: u64 },
+    Exponential { mean_ms: u64 },
+}
+
+// How long each virtual user waits between the steps of an iteration and
+// between iterations. With an interval set, iterations of a virtual user
+// start no more often than once per interval.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Pacing {
+    pub step_think: Option<ThinkTime>,
+    pub iteration_think: Option<ThinkTime>,
+    pub interval: Option<Duration>,
+}
+
+// With virtual users enabled every concurrent slot keeps its cookies and
+// extracted variables from one iteration to the next, optionally starting a
+// fresh session every `reset_every` iterations.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Sessions {
+    pub virtual_users: bool,
+    pub reset_every: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSpec {
+    pub run_id: String,
+    pub requests: Vec<RequestSpec>,
+    pub strategy: AttackStrategy,
+    pub max_concurrency: u32,
+    pub rate: Option<f64>,
+    pub stages: Vec<Stage>,
+    pub duration: Option<Duration>,
+    pub max_requests: Option<u64>,
+    pub percentiles: Vec<f64>,
+    pub variables: HashMap<String, String>,
+    pub feeders: Vec<Feeder>,
+    pub abort_on_failure: bool,
+    pub connect_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub pool: Pool,
+    pub sessions: Sessions,
+    pub pacing: Pacing,
+    // The expected time between requests of a closed-model run, for
+    // HdrHistogram's coordinated omission correction.
+    pub expected_interval: Option<Duration>,
+    // How far back the sliding window in each status reaches.
+    pub window: Duration,
+    pub shard: Shard,
+}
+
+impl RunSpec {
+    // Runs that divide a total between their workers can't take on workers
+    // that connect after they've started.
+    pub fn fixed_split(&self) -> bool {
+        self.rate.is_some()
+            || self.max_requests.is_some()
+            || self.feeders.iter().any(|f| f.partition)
+    }
+
+    // The share of requests each spec should get under the run's strategy.
+    pub fn planned_mix(&self) -> Vec<f64> {
+        let weights: Vec<f64> = match self.strategy {
+            AttackStrategy::Weighted => self.requests.iter().map(|r| r.weight).collect(),
+            _ => vec![1.0; self.requests.len()],
+        };
+        let total: f64 = weights.iter().sum();
+        weights
+            .iter()
+            .map(|w| if total > 0.0 { w / total } else { 0.0 })
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Command {
     Stop,
-    Start {
-        requests: Vec<RequestSpec>,
-        strategy: AttackStrategy,
-        max_concurrency: u32,
-    },
+    Start(Box<RunSpec>),
     Reset,
 }
 
 impl Command {
-    pub fn start(
-        requests: Vec<RequestSpec>,
-        strategy: AttackStrategy,
-        max_concurrency: u32,
-    ) -> Command {
-        Command::Start {
-            requests,
-            strategy,
-            max_concurrency,
-        }
+    pub fn start(spec: RunSpec) -> Command {
+        Command::Start(Box::new(spec))
     }
 
     pub fn stop() -> Command {
@@ -113,12 +365,119 @@ pub enum WorkerState {
     Busy,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorKind {
+    ConnectionRefused,
+    ConnectionReset,
+    Dns,
+    Tls,
+    Timeout,
+    InvalidUrl,
+    Protocol,
+    Other,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 8] = [
+        ErrorKind::ConnectionRefused,
+        ErrorKind::ConnectionReset,
+        ErrorKind::Dns,
+        ErrorKind::Tls,
+        ErrorKind::Timeout,
+        ErrorKind::InvalidUrl,
+        ErrorKind::Protocol,
+        ErrorKind::Other,
+    ];
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ErrorMessage {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub count: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Percentile {
+    pub percentile: f64,
+    pub value: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Latency {
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stdev: f64,
+    pub percentiles: Vec<Percentile>,
+    pub histogram: String,
+}
+
+// Totals for a recent stretch of the run rather than all of it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Window {
+    pub length: Duration,
+    pub count: u32,
+    pub count_1xx: u32,
+    pub count_2xx: u32,
+    pub count_3xx: u32,
+    pub count_4xx: u32,
+    pub count_5xx: u32,
+    pub count_fail: u32,
+    pub count_timeout: u32,
+    pub latency: Latency,
+}
+
+// Timings for connections opened during the run. Reused counts requests that
+// were served from the pool without going through the connector.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Connections {
+    pub new: u32,
+    pub reused: u32,
+    pub dns: Latency,
+    pub connect: Latency,
+    pub tls: Latency,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CheckStatus {
+    pub check: String,
+    pub pass: u32,
+    pub fail: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SpecStatus {
+    pub index: usize,
+    pub name: Option<String>,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stdev: f64,
+    pub percentiles: Vec<Percentile>,
+    pub histogram: String,
+    pub count: u32,
+    pub count_1xx: u32,
+    pub count_2xx: u32,
+    pub count_3xx: u32,
+    pub count_4xx: u32,
+    pub count_5xx: u32,
+    pub count_fail: u32,
+    pub count_timeout: u32,
+    pub count_aborted: u32,
+    pub checks: Vec<CheckStatus>,
+    pub planned_share: f64,
+}
+
+// All latency figures are in microseconds.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Status {
+    pub run_id: Option<String>,
     pub hostname: Option<String>,
     pub socket: Option<SocketAddr>,
     pub state: WorkerState,
     pub elapsed: Option<Duration>,
+    pub stage: Option<ActiveStage>,
     pub tasks: u32,
     pub task_queue: u32,
     pub tasks_max: u32,
@@ -128,6 +487,14 @@ pub struct Status {
     pub stdev: f64,
     pub median: u64,
     pub p90: u64,
+    pub percentiles: Vec<Percentile>,
+    pub histogram: String,
+    // Measured from when each request was meant to start; see Stats::record.
+    pub corrected: Latency,
+    pub ttfb: Latency,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub connections: Connections,
     pub count: u32,
     pub count_1xx: u32,
     pub count_2xx: u32,
@@ -135,6 +502,18 @@ pub struct Status {
     pub count_4xx: u32,
     pub count_5xx: u32,
     pub count_fail: u32,
+    pub count_timeout: u32,
+    pub timeouts: Latency,
+    pub count_aborted: u32,
+    pub count_dropped: u32,
+    pub count_late: u32,
+    pub status_codes: BTreeMap<u16, u32>,
+    pub errors: BTreeMap<ErrorKind, u32>,
+    pub error_messages: Vec<ErrorMessage>,
+    pub specs: Vec<SpecStatus>,
+    // Since the previous heartbeat, and over the run's sliding window.
+    pub interval: Window,
+    pub window: Window,
 }
 
 impl Status {
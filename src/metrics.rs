@@ -0,0 +1,450 @@
+use crate::{
+    messages,
+    stats::{Snapshot, StatsCollector, Status, WorkerState},
+    worker_server::{Health, Link},
+};
+use std::{
+    fmt::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Renders what the coordinator knows in the Prometheus text format. Worker
+// series are labelled with the worker they came from; the same figures for
+// the whole cluster are exported under minuteman_cluster_* so that summing the
+// worker series never counts anything twice.
+//
+// A worker with its own listener renders just its own figures, unlabelled,
+// under the same minuteman_worker_* names.
+
+const US_PER_SEC: f64 = 1_000_000.0;
+const WORKER_STATES: [WorkerState; 4] = [
+    WorkerState::Connected,
+    WorkerState::Idle,
+    WorkerState::Busy,
+    WorkerState::Disconnected,
+];
+
+type Labels = Vec<(&'static str, String)>;
+type Samples = Vec<(Labels, f64)>;
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &[(&'static str, String)], value: f64) {
+    let _ = write!(out, "{}", name);
+    for (i, (k, v)) in labels.iter().enumerate() {
+        let sep = if i == 0 { '{' } else { ',' };
+        let _ = write!(out, "{}{}=\"{}\"", sep, k, escape(v));
+    }
+    if !labels.is_empty() {
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn write_family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(Labels, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples.iter() {
+        write_sample(out, name, labels, *value);
+    }
+}
+
+fn state_name(state: WorkerState) -> String {
+    format!("{:?}", state).to_lowercase()
+}
+
+fn with(labels: &[(&'static str, String)], key: &'static str, value: String) -> Labels {
+    let mut labels = labels.to_vec();
+    labels.push((key, value));
+    labels
+}
+
+// A latency summary in seconds, from the percentiles the run asked for.
+struct Summary {
+    mean: f64,
+    count: u32,
+    quantiles: Vec<(f64, u64)>,
+}
+
+fn summaries(s: &Snapshot) -> Vec<(&'static str, &'static str, Summary)> {
+    let completed = s.count.saturating_sub(s.count_timeout);
+    let responses = s.count_1xx + s.count_2xx + s.count_3xx + s.count_4xx + s.count_5xx;
+    let quantiles =
+        |p: &[crate::messages::Percentile]| p.iter().map(|p| (p.percentile, p.value)).collect();
+    vec![
+        (
+            "latency_seconds",
+            "Time from sending a request to reading the whole response.",
+            Summary {
+                mean: s.mean,
+                count: completed,
+                quantiles: quantiles(&s.percentiles),
+            },
+        ),
+        (
+            "corrected_latency_seconds",
+            "Latency measured from when each request was meant to start.",
+            Summary {
+                mean: s.corrected.mean,
+                count: completed,
+                quantiles: quantiles(&s.corrected.percentiles),
+            },
+        ),
+        (
+            "ttfb_seconds",
+            "Time from sending a request to receiving the response headers.",
+            Summary {
+                mean: s.ttfb.mean,
+                count: responses,
+                quantiles: quantiles(&s.ttfb.percentiles),
+            },
+        ),
+    ]
+}
+
+fn write_summaries(out: &mut String, prefix: &str, targets: &[(Labels, &Snapshot)]) {
+    let per_target: Vec<_> = targets.iter().map(|(l, s)| (l, summaries(s))).collect();
+    let families = per_target.first().map(|(_, s)| s.len()).unwrap_or(0);
+    for i in 0..families {
+        let (name, help, _) = &per_target[0].1[i];
+        let name = format!("{}_{}", prefix, name);
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} summary", name);
+        for (labels, summaries) in per_target.iter() {
+            let summary = &summaries[i].2;
+            for (percentile, value) in summary.quantiles.iter() {
+                // Rounded so 99.9 comes out as 0.999 rather than float noise.
+                let quantile = (percentile * 10_000.0).round() / 1_000_000.0;
+                let labels = with(labels, "quantile", quantile.to_string());
+                write_sample(out, &name, &labels, *value as f64 / US_PER_SEC);
+            }
+            let sum = summary.mean * f64::from(summary.count) / US_PER_SEC;
+            write_sample(out, &format!("{}_sum", name), labels, sum);
+            write_sample(
+                out,
+                &format!("{}_count", name),
+                labels,
+                f64::from(summary.count),
+            );
+        }
+    }
+}
+
+type Extract = fn(&Snapshot) -> Samples;
+
+fn one(value: impl Into<f64>) -> Samples {
+    vec![(Vec::new(), value.into())]
+}
+
+// Every family derived from a snapshot: name, type, help and how to get its
+// samples.
+fn snapshot_families() -> Vec<(&'static str, &'static str, &'static str, Extract)> {
+    vec![
+        (
+            "requests_total",
+            "counter",
+            "Requests finished, whatever the outcome.",
+            |s| one(s.count),
+        ),
+        (
+            "responses_total",
+            "counter",
+            "Responses by status class.",
+            |s| {
+                [
+                    ("1xx", s.count_1xx),
+                    ("2xx", s.count_2xx),
+                    ("3xx", s.count_3xx),
+                    ("4xx", s.count_4xx),
+                    ("5xx", s.count_5xx),
+                ]
+                .iter()
+                .map(|(class, count)| (vec![("class", class.to_string())], f64::from(*count)))
+                .collect()
+            },
+        ),
+        (
+            "responses_by_code_total",
+            "counter",
+            "Responses by status code.",
+            |s| {
+                s.status_codes
+                    .iter()
+                    .map(|(code, count)| (vec![("code", code.to_string())], f64::from(*count)))
+                    .collect()
+            },
+        ),
+        (
+            "failures_total",
+            "counter",
+            "Requests that got no response.",
+            |s| one(s.count_fail),
+        ),
+        (
+            "timeouts_total",
+            "counter",
+            "Requests that timed out.",
+            |s| one(s.count_timeout),
+        ),
+        (
+            "errors_total",
+            "counter",
+            "Request errors by category.",
+            |s| {
+                s.errors
+                    .iter()
+                    .map(|(kind, count)| (vec![("kind", format!("{:?}", kind))], f64::from(*count)))
+                    .collect()
+            },
+        ),
+        (
+            "aborted_total",
+            "counter",
+            "Scenario iterations abandoned after a failed step.",
+            |s| one(s.count_aborted),
+        ),
+        (
+            "dropped_total",
+            "counter",
+            "Scheduled requests dropped because every task was busy.",
+            |s| one(s.count_dropped),
+        ),
+        (
+            "late_total",
+            "counter",
+            "Scheduled requests that started late.",
+            |s| one(s.count_late),
+        ),
+        (
+            "bytes_sent_total",
+            "counter",
+            "Bytes sent in requests.",
+            |s| one(s.bytes_sent as f64),
+        ),
+        (
+            "bytes_received_total",
+            "counter",
+            "Bytes received in responses.",
+            |s| one(s.bytes_received as f64),
+        ),
+        (
+            "connections_opened_total",
+            "counter",
+            "Connections opened.",
+            |s| one(s.connections.new),
+        ),
+        (
+            "connections_reused_total",
+            "counter",
+            "Requests sent on a pooled connection.",
+            |s| one(s.connections.reused),
+        ),
+        (
+            "spec_requests_total",
+            "counter",
+            "Requests finished per request spec.",
+            |s| {
+                s.specs
+                    .iter()
+                    .map(|spec| {
+                        (
+                            vec![
+                                ("spec", spec.index.to_string()),
+                                ("name", spec.name.clone().unwrap_or_default()),
+                            ],
+                            f64::from(spec.count),
+                        )
+                    })
+                    .collect()
+            },
+        ),
+        ("tasks", "gauge", "Tasks in flight.", |s| one(s.tasks)),
+        (
+            "task_queue",
+            "gauge",
+            "Tasks waiting on the scheduler.",
+            |s| one(s.task_queue),
+        ),
+        ("tasks_max", "gauge", "Most tasks allowed in flight.", |s| {
+            one(s.tasks_max)
+        }),
+    ]
+}
+
+fn write_snapshots(out: &mut String, prefix: &str, targets: &[(Labels, &Snapshot)]) {
+    if targets.is_empty() {
+        return;
+    }
+    for (name, kind, help, extract) in snapshot_families() {
+        let samples: Samples = targets
+            .iter()
+            .flat_map(|(labels, s)| {
+                extract(s).into_iter().map(move |(extra, value)| {
+                    let mut l = labels.clone();
+                    l.extend(extra);
+                    (l, value)
+                })
+            })
+            .collect();
+        write_family(out, &format!("{}_{}", prefix, name), kind, help, &samples);
+    }
+    write_summaries(out, prefix, targets);
+}
+
+fn epoch_secs(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+pub fn render(collector: &StatsCollector) -> String {
+    let mut out = String::new();
+    if let Some(run) = collector.run() {
+        let labels = vec![
+            ("run_id", run.id.clone()),
+            ("state", format!("{:?}", run.state).to_lowercase()),
+        ];
+        write_family(
+            &mut out,
+            "minuteman_run_info",
+            "gauge",
+            "The current run.",
+            &[(labels, 1.0)],
+        );
+        write_family(
+            &mut out,
+            "minuteman_run_started_seconds",
+            "gauge",
+            "When the current run started, in seconds since the epoch.",
+            &[(vec![("run_id", run.id.clone())], epoch_secs(run.started))],
+        );
+        if let Some(finished) = run.finished {
+            write_family(
+                &mut out,
+                "minuteman_run_finished_seconds",
+                "gauge",
+                "When the current run finished, in seconds since the epoch.",
+                &[(vec![("run_id", run.id.clone())], epoch_secs(finished))],
+            );
+        }
+    }
+    out += &collector.with_stats(|map| {
+        let mut out = String::new();
+        let mut workers: Vec<_> = map.values().collect();
+        workers.sort_by_key(|w| w.id);
+        let worker_labels = |w: &Status| -> Labels {
+            vec![
+                ("worker", w.id.to_string()),
+                ("hostname", w.hostname.clone().unwrap_or_default()),
+            ]
+        };
+        let states: Samples = workers
+            .iter()
+            .flat_map(|w| {
+                let labels = worker_labels(w);
+                WORKER_STATES.iter().map(move |state| {
+                    let value = if *state == w.state { 1.0 } else { 0.0 };
+                    (with(&labels, "state", state_name(*state)), value)
+                })
+            })
+            .collect();
+        write_family(
+            &mut out,
+            "minuteman_worker_state",
+            "gauge",
+            "Whether the worker is in the given state.",
+            &states,
+        );
+        let counts: Samples = WORKER_STATES
+            .iter()
+            .map(|state| {
+                let n = workers.iter().filter(|w| w.state == *state).count();
+                (vec![("state", state_name(*state))], n as f64)
+            })
+            .collect();
+        write_family(
+            &mut out,
+            "minuteman_cluster_workers",
+            "gauge",
+            "Workers in each state.",
+            &counts,
+        );
+        let targets: Vec<(Labels, &Snapshot)> = workers
+            .iter()
+            .filter_map(|w| w.snapshots.front().map(|s| (worker_labels(w), s)))
+            .collect();
+        write_snapshots(&mut out, "minuteman_worker", &targets);
+        out
+    });
+    if let Some(cluster) = collector.aggregate() {
+        write_snapshots(&mut out, "minuteman_cluster", &[(Vec::new(), &cluster)]);
+    }
+    out
+}
+
+pub fn render_worker(status: messages::Status, health: &Health) -> String {
+    let mut out = String::new();
+    if let Some(run_id) = status.run_id.clone() {
+        write_family(
+            &mut out,
+            "minuteman_run_info",
+            "gauge",
+            "The run this worker was last given.",
+            &[(vec![("run_id", run_id)], 1.0)],
+        );
+    }
+    let states: Samples = [messages::WorkerState::Idle, messages::WorkerState::Busy]
+        .iter()
+        .map(|state| {
+            let value = if *state == status.state { 1.0 } else { 0.0 };
+            (
+                vec![("state", format!("{:?}", state).to_lowercase())],
+                value,
+            )
+        })
+        .collect();
+    write_family(
+        &mut out,
+        "minuteman_worker_state",
+        "gauge",
+        "Whether the worker is in the given state.",
+        &states,
+    );
+    let connected = if health.link() == Link::Connected {
+        1.0
+    } else {
+        0.0
+    };
+    write_family(
+        &mut out,
+        "minuteman_worker_coordinator_connected",
+        "gauge",
+        "Whether the worker is connected to the coordinator.",
+        &[(Vec::new(), connected)],
+    );
+    write_family(
+        &mut out,
+        "minuteman_worker_coordinator_connects_total",
+        "counter",
+        "Connections made to the coordinator.",
+        &[(Vec::new(), f64::from(health.connects()))],
+    );
+    write_family(
+        &mut out,
+        "minuteman_worker_start_time_seconds",
+        "gauge",
+        "When the worker process started, in seconds since the epoch.",
+        &[(Vec::new(), epoch_secs(health.started()))],
+    );
+    let snapshot = Snapshot::from(status);
+    write_snapshots(&mut out, "minuteman_worker", &[(Vec::new(), &snapshot)]);
+    out
+}
@@ -0,0 +1,24 @@
+use crate::messages::ThinkTime;
+use rand::Rng;
+use std::{f64::consts::PI, time::Duration};
+
+// Normal samples below zero are clamped to zero.
+pub fn sample(think: &ThinkTime) -> Duration {
+    let mut rng = rand::thread_rng();
+    let ms = match *think {
+        ThinkTime::Constant(ms) => ms as f64,
+        ThinkTime::Uniform { min_ms, max_ms } if min_ms < max_ms => {
+            rng.gen_range(min_ms..=max_ms) as f64
+        }
+        ThinkTime::Uniform { min_ms, .. } => min_ms as f64,
+        ThinkTime::Normal { mean_ms, stdev_ms } => {
+            // Box-Muller; 1 - gen() is in (0, 1], which keeps ln finite.
+            let u1 = 1.0 - rng.gen::<f64>();
+            let u2 = rng.gen::<f64>();
+            let z = (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+            mean_ms as f64 + z * stdev_ms as f64
+        }
+        ThinkTime::Exponential { mean_ms } => -(mean_ms as f64) * (1.0 - rng.gen::<f64>()).ln(),
+    };
+    Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+}
@@ -0,0 +1,69 @@
+use crate::messages::{ActiveStage, Interpolation, Stage};
+use std::time::Duration;
+
+// Resolves the target concurrency or rate for a point in time. With no stages
+// the base target applies for the whole run; with stages the run is finished
+// once the last one has elapsed.
+#[derive(Debug, Clone)]
+pub struct LoadProfile {
+    base: f64,
+    stages: Vec<Stage>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Target {
+    pub value: f64,
+    pub stage: Option<ActiveStage>,
+}
+
+impl LoadProfile {
+    pub fn new(base: f64, stages: Vec<Stage>) -> LoadProfile {
+        LoadProfile { base, stages }
+    }
+
+    pub fn max(&self) -> f64 {
+        self.stages.iter().map(|s| s.target).fold(
+            if self.stages.is_empty() {
+                self.base
+            } else {
+                0.0
+            },
+            f64::max,
+        )
+    }
+
+    pub fn at(&self, elapsed: Duration) -> Option<Target> {
+        if self.stages.is_empty() {
+            return Some(Target {
+                value: self.base,
+                stage: None,
+            });
+        }
+        let mut from = 0.0;
+        let mut stage_start = Duration::from_secs(0);
+        for (index, stage) in self.stages.iter().enumerate() {
+            let stage_end = stage_start + stage.duration;
+            if elapsed < stage_end {
+                let into_stage = elapsed - stage_start;
+                let value = match stage.interpolation {
+                    Interpolation::Step => stage.target,
+                    Interpolation::Linear => {
+                        let progress = into_stage.as_secs_f64() / stage.duration.as_secs_f64();
+                        from + (stage.target - from) * progress
+                    }
+                };
+                return Some(Target {
+                    value,
+                    stage: Some(ActiveStage {
+                        index,
+                        target: value,
+                        elapsed: into_stage,
+                    }),
+                });
+            }
+            from = stage.target;
+            stage_start = stage_end;
+        }
+        None
+    }
+}
@@ -1,22 +1,93 @@
 use crate::messages;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
+    convert::TryInto,
     net::SocketAddr,
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicU32, AtomicU64, Ordering},
         Arc, RwLock,
     },
     time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{Error, Result};
-use hdrhistogram::Histogram;
+use hdrhistogram::{
+    serialization::{Deserializer, Serializer, V2DeflateSerializer},
+    Histogram,
+};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+// Histograms travel between workers and the coordinator as base64 encoded,
+// deflated HdrHistogram V2 blobs so the coordinator can merge them losslessly.
+pub fn encode_histogram(histo: &Histogram<u64>) -> Result<String> {
+    let mut buf = Vec::new();
+    V2DeflateSerializer::new().serialize(histo, &mut buf)?;
+    Ok(base64::encode(&buf))
+}
+
+pub fn decode_histogram(encoded: &str) -> Result<Histogram<u64>> {
+    let buf = base64::decode(encoded)?;
+    let mut histo: Histogram<u64> = Deserializer::new().deserialize(&mut buf.as_slice())?;
+    histo.auto(true);
+    Ok(histo)
+}
+
+pub fn percentiles_of<I>(histo: &Histogram<u64>, percentiles: I) -> Vec<messages::Percentile>
+where
+    I: Iterator<Item = f64>,
+{
+    percentiles
+        .map(|p| messages::Percentile {
+            percentile: p,
+            value: histo.value_at_percentile(p),
+        })
+        .collect()
+}
+
+pub fn as_micros(d: Duration) -> u64 {
+    d.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+pub fn latency_of(histo: &Histogram<u64>, percentiles: &[f64]) -> messages::Latency {
+    messages::Latency {
+        min: histo.min(),
+        max: histo.max(),
+        mean: histo.mean(),
+        stdev: histo.stdev(),
+        percentiles: percentiles_of(histo, percentiles.iter().copied()),
+        histogram: encode_histogram(histo).unwrap_or_default(),
+    }
+}
+
+// Distinct error messages tracked per worker, and how many of the most
+// frequent are reported.
+const MAX_ERROR_MESSAGES: usize = 100;
+const TOP_ERROR_MESSAGES: usize = 10;
+
+fn top_errors<I>(errors: I) -> Vec<messages::ErrorMessage>
+where
+    I: Iterator<Item = messages::ErrorMessage>,
+{
+    let mut errors: Vec<_> = errors.collect();
+    errors.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.message.cmp(&b.message))
+    });
+    errors.truncate(TOP_ERROR_MESSAGES);
+    errors
+}
 
 struct StatsInner {
+    run_id: Option<String>,
+    percentiles: Vec<f64>,
     started: Option<Instant>,
     elapsed: Option<Duration>,
     state: messages::WorkerState,
+    stage: Option<messages::ActiveStage>,
+    expected_interval_us: Option<u64>,
+    window: Duration,
 }
 #[derive(Debug, Default)]
 struct Counters {
@@ -27,6 +98,13 @@ struct Counters {
     count_4xx: AtomicU32,
     count_5xx: AtomicU32,
     count_fail: AtomicU32,
+    count_timeout: AtomicU32,
+    count_aborted: AtomicU32,
+    count_dropped: AtomicU32,
+    count_late: AtomicU32,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    errors: [AtomicU32; messages::ErrorKind::ALL.len()],
 }
 
 impl Counters {
@@ -62,6 +140,61 @@ impl Counters {
         self.count_fail.fetch_add(1, Ordering::AcqRel);
     }
 
+    pub fn record_status(&self, status: Option<u16>) {
+        self.inc_count();
+        match status {
+            Some(status) => {
+                if status >= 500 {
+                    self.inc_5xx();
+                } else if status >= 400 {
+                    self.inc_4xx();
+                } else if status >= 300 {
+                    self.inc_3xx();
+                } else if status >= 200 {
+                    self.inc_2xx();
+                } else if status >= 100 {
+                    self.inc_1xx();
+                }
+            }
+            None => self.inc_fail(),
+        }
+    }
+
+    // Timeouts are their own outcome rather than a failure.
+    pub fn record_timeout(&self) {
+        self.inc_count();
+        self.count_timeout.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn inc_aborted(&self) {
+        self.count_aborted.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn inc_dropped(&self) {
+        self.count_dropped.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn inc_late(&self) {
+        self.count_late.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn add_bytes(&self, sent: u64, received: u64) {
+        self.bytes_sent.fetch_add(sent, Ordering::AcqRel);
+        self.bytes_received.fetch_add(received, Ordering::AcqRel);
+    }
+
+    pub fn inc_error(&self, kind: messages::ErrorKind) {
+        self.errors[kind as usize].fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn errors(&self) -> BTreeMap<messages::ErrorKind, u32> {
+        messages::ErrorKind::ALL
+            .iter()
+            .map(|k| (*k, self.errors[*k as usize].load(Ordering::Acquire)))
+            .filter(|(_, c)| *c > 0)
+            .collect()
+    }
+
     pub fn clear(&self) {
         self.count.store(0, Ordering::SeqCst);
         self.count_1xx.store(0, Ordering::SeqCst);
@@ -70,15 +203,179 @@ impl Counters {
         self.count_4xx.store(0, Ordering::SeqCst);
         self.count_5xx.store(0, Ordering::SeqCst);
         self.count_fail.store(0, Ordering::SeqCst);
+        self.count_timeout.store(0, Ordering::SeqCst);
+        self.count_aborted.store(0, Ordering::SeqCst);
+        self.count_dropped.store(0, Ordering::SeqCst);
+        self.count_late.store(0, Ordering::SeqCst);
+        self.bytes_sent.store(0, Ordering::SeqCst);
+        self.bytes_received.store(0, Ordering::SeqCst);
+        for e in self.errors.iter() {
+            e.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+fn new_histogram() -> Histogram<u64> {
+    let mut histo = Histogram::new_with_bounds(1, 60_000_000, 3).unwrap();
+    histo.auto(true);
+    histo
+}
+
+struct CheckStats {
+    check: String,
+    pass: AtomicU32,
+    fail: AtomicU32,
+}
+
+// Counters and latencies for a single RequestSpec, indexed by its position in
+// the run's request list.
+struct SpecStats {
+    name: Option<String>,
+    planned_share: f64,
+    counters: Counters,
+    histo: RwLock<Histogram<u64>>,
+    checks: Vec<CheckStats>,
+}
+
+impl SpecStats {
+    fn new(spec: &messages::RequestSpec, planned_share: f64) -> SpecStats {
+        SpecStats {
+            name: spec.name.clone(),
+            planned_share,
+            counters: Counters::new(),
+            histo: RwLock::new(new_histogram()),
+            checks: spec
+                .checks
+                .iter()
+                .map(|c| CheckStats {
+                    check: c.to_string(),
+                    pass: AtomicU32::new(0),
+                    fail: AtomicU32::new(0),
+                })
+                .collect(),
+        }
+    }
+
+    fn as_message(&self, index: usize, percentiles: &[f64]) -> messages::SpecStatus {
+        let histo = self.histo.read().unwrap();
+        let counters = &self.counters;
+        messages::SpecStatus {
+            index,
+            name: self.name.clone(),
+            min: histo.min(),
+            max: histo.max(),
+            mean: histo.mean(),
+            stdev: histo.stdev(),
+            percentiles: percentiles_of(&histo, percentiles.iter().copied()),
+            histogram: encode_histogram(&histo).unwrap_or_default(),
+            count: counters.count.load(Ordering::Acquire),
+            count_1xx: counters.count_1xx.load(Ordering::Acquire),
+            count_2xx: counters.count_2xx.load(Ordering::Acquire),
+            count_3xx: counters.count_3xx.load(Ordering::Acquire),
+            count_4xx: counters.count_4xx.load(Ordering::Acquire),
+            count_5xx: counters.count_5xx.load(Ordering::Acquire),
+            count_fail: counters.count_fail.load(Ordering::Acquire),
+            count_timeout: counters.count_timeout.load(Ordering::Acquire),
+            count_aborted: counters.count_aborted.load(Ordering::Acquire),
+            checks: self
+                .checks
+                .iter()
+                .map(|c| messages::CheckStatus {
+                    check: c.check.clone(),
+                    pass: c.pass.load(Ordering::Acquire),
+                    fail: c.fail.load(Ordering::Acquire),
+                })
+                .collect(),
+            planned_share: self.planned_share,
+        }
     }
 }
 
 impl StatsInner {
     fn new() -> StatsInner {
         StatsInner {
+            run_id: None,
+            percentiles: Vec::new(),
             started: None,
             elapsed: None,
             state: messages::WorkerState::Idle,
+            stage: None,
+            expected_interval_us: None,
+            window: Duration::default(),
+        }
+    }
+}
+
+// Cumulative totals as of one heartbeat. Interval and window figures are the
+// difference between two of these.
+struct Checkpoint {
+    at: Instant,
+    count: u32,
+    count_1xx: u32,
+    count_2xx: u32,
+    count_3xx: u32,
+    count_4xx: u32,
+    count_5xx: u32,
+    count_fail: u32,
+    count_timeout: u32,
+    histo: Histogram<u64>,
+}
+
+impl Checkpoint {
+    fn since(&self, earlier: &Checkpoint, percentiles: &[f64]) -> messages::Window {
+        let mut histo = self.histo.clone();
+        let _ = histo.subtract(&earlier.histo);
+        messages::Window {
+            length: self.at - earlier.at,
+            count: self.count.saturating_sub(earlier.count),
+            count_1xx: self.count_1xx.saturating_sub(earlier.count_1xx),
+            count_2xx: self.count_2xx.saturating_sub(earlier.count_2xx),
+            count_3xx: self.count_3xx.saturating_sub(earlier.count_3xx),
+            count_4xx: self.count_4xx.saturating_sub(earlier.count_4xx),
+            count_5xx: self.count_5xx.saturating_sub(earlier.count_5xx),
+            count_fail: self.count_fail.saturating_sub(earlier.count_fail),
+            count_timeout: self.count_timeout.saturating_sub(earlier.count_timeout),
+            latency: latency_of(&histo, percentiles),
+        }
+    }
+}
+
+struct ConnectionStats {
+    attempts: AtomicU32,
+    new: AtomicU32,
+    dns: RwLock<Histogram<u64>>,
+    connect: RwLock<Histogram<u64>>,
+    tls: RwLock<Histogram<u64>>,
+}
+
+impl ConnectionStats {
+    fn new() -> ConnectionStats {
+        ConnectionStats {
+            attempts: AtomicU32::new(0),
+            new: AtomicU32::new(0),
+            dns: RwLock::new(new_histogram()),
+            connect: RwLock::new(new_histogram()),
+            tls: RwLock::new(new_histogram()),
+        }
+    }
+
+    fn reset(&self) {
+        self.attempts.store(0, Ordering::SeqCst);
+        self.new.store(0, Ordering::SeqCst);
+        self.dns.write().unwrap().reset();
+        self.connect.write().unwrap().reset();
+        self.tls.write().unwrap().reset();
+    }
+
+    // hyper doesn't say whether a request went out on a pooled connection, so
+    // anything that never reached the connector is counted as reused.
+    fn as_message(&self, requests: u32, percentiles: &[f64]) -> messages::Connections {
+        messages::Connections {
+            new: self.new.load(Ordering::Acquire),
+            reused: requests.saturating_sub(self.attempts.load(Ordering::Acquire)),
+            dns: latency_of(&self.dns.read().unwrap(), percentiles),
+            connect: latency_of(&self.connect.read().unwrap(), percentiles),
+            tls: latency_of(&self.tls.read().unwrap(), percentiles),
         }
     }
 }
@@ -88,6 +385,14 @@ pub struct Stats {
     inner: Arc<RwLock<StatsInner>>,
     counters: Arc<Counters>,
     histo: Arc<RwLock<Histogram<u64>>>,
+    corrected: Arc<RwLock<Histogram<u64>>>,
+    ttfb: Arc<RwLock<Histogram<u64>>>,
+    timeouts: Arc<RwLock<Histogram<u64>>>,
+    connections: Arc<ConnectionStats>,
+    specs: Arc<RwLock<Vec<SpecStats>>>,
+    checkpoints: Arc<RwLock<VecDeque<Checkpoint>>>,
+    status_codes: Arc<RwLock<BTreeMap<u16, u32>>>,
+    error_messages: Arc<RwLock<HashMap<(messages::ErrorKind, String), u32>>>,
     task_gauge: Arc<AtomicU32>,
     task_queue: Arc<AtomicU32>,
     task_max: Arc<AtomicU32>,
@@ -95,29 +400,51 @@ pub struct Stats {
 
 impl Stats {
     pub fn new() -> Stats {
-        let mut histo = Histogram::new_with_max(60_000, 5).unwrap();
-        histo.auto(true);
         Stats {
             inner: Arc::new(RwLock::new(StatsInner::new())),
             counters: Arc::new(Counters::new()),
-            histo: Arc::new(RwLock::new(histo)),
+            histo: Arc::new(RwLock::new(new_histogram())),
+            corrected: Arc::new(RwLock::new(new_histogram())),
+            ttfb: Arc::new(RwLock::new(new_histogram())),
+            timeouts: Arc::new(RwLock::new(new_histogram())),
+            connections: Arc::new(ConnectionStats::new()),
+            specs: Arc::new(RwLock::new(Vec::new())),
+            checkpoints: Arc::new(RwLock::new(VecDeque::new())),
+            status_codes: Arc::new(RwLock::new(BTreeMap::new())),
+            error_messages: Arc::new(RwLock::new(HashMap::new())),
             task_gauge: Arc::new(AtomicU32::new(0)),
             task_queue: Arc::new(AtomicU32::new(0)),
             task_max: Arc::new(AtomicU32::new(0)),
         }
     }
 
-    pub fn start(&self) {
+    pub fn start(&self, spec: &messages::RunSpec) {
         self.reset();
         let mut stats = self.inner.write().unwrap();
+        stats.run_id = Some(spec.run_id.clone());
+        stats.percentiles = spec.percentiles.clone();
+        stats.expected_interval_us = spec.expected_interval.map(as_micros);
+        stats.window = spec.window;
+        *self.specs.write().unwrap() = spec
+            .requests
+            .iter()
+            .zip(spec.planned_mix())
+            .map(|(r, share)| SpecStats::new(r, share))
+            .collect();
         stats.state = messages::WorkerState::Busy;
         stats.started = Some(Instant::now());
+        drop(stats);
+        self.checkpoint();
     }
 
     pub fn stop(&self) {
         let mut stats = self.inner.write().unwrap();
-        stats.elapsed = stats.started.map(|s| s.elapsed());
+        // A run that already finished keeps its length when stopped again.
+        if stats.state == messages::WorkerState::Busy {
+            stats.elapsed = stats.started.map(|s| s.elapsed());
+        }
         stats.state = messages::WorkerState::Idle;
+        stats.stage = None;
     }
 
     pub fn reset(&self) {
@@ -125,9 +452,22 @@ impl Stats {
         let counters = self.counters.clone();
         let mut histo = self.histo.write().unwrap();
         histo.reset();
+        self.corrected.write().unwrap().reset();
+        self.ttfb.write().unwrap().reset();
+        self.timeouts.write().unwrap().reset();
+        self.connections.reset();
+        self.specs.write().unwrap().clear();
+        self.checkpoints.write().unwrap().clear();
+        self.status_codes.write().unwrap().clear();
+        self.error_messages.write().unwrap().clear();
+        stats.run_id = None;
+        stats.percentiles.clear();
         stats.elapsed = None;
         stats.started = None;
         stats.state = messages::WorkerState::Idle;
+        stats.stage = None;
+        stats.expected_interval_us = None;
+        stats.window = Duration::default();
         counters.clear();
         self.task_gauge.store(0, Ordering::Release);
         self.task_queue.store(0, Ordering::Release);
@@ -138,6 +478,10 @@ impl Stats {
         self.task_max.store(max, Ordering::Release);
     }
 
+    pub fn record_stage(&mut self, stage: Option<messages::ActiveStage>) {
+        self.inner.write().unwrap().stage = stage;
+    }
+
     pub fn record_current_tasks(&mut self, current: u32) {
         self.task_gauge.store(current, Ordering::Release);
     }
@@ -146,27 +490,125 @@ impl Stats {
         self.task_queue.store(current, Ordering::Release);
     }
 
-    pub fn record(&mut self, status: Option<u16>, elapsed_ms: u64) {
-        let counters = self.counters.clone();
-        counters.inc_count();
-        match status {
-            Some(status) => {
-                if status >= 500 {
-                    counters.inc_5xx();
-                } else if status >= 400 {
-                    counters.inc_4xx();
-                } else if status >= 300 {
-                    counters.inc_3xx();
-                } else if status >= 200 {
-                    counters.inc_2xx();
-                } else if status >= 100 {
-                    counters.inc_1xx();
+    pub fn record_dropped(&mut self) {
+        self.counters.inc_dropped();
+    }
+
+    pub fn record_late(&mut self) {
+        self.counters.inc_late();
+    }
+
+    // `corrected_us` counts from when the request should have started, which
+    // is later than when it did whenever the schedule slipped. With an
+    // expected interval HdrHistogram also fills in the requests a stalled
+    // closed-model run never sent.
+    pub fn record(&mut self, spec: usize, status: Option<u16>, elapsed_us: u64, corrected_us: u64) {
+        self.counters.record_status(status);
+        if let Some(code) = status {
+            *self.status_codes.write().unwrap().entry(code).or_insert(0) += 1;
+        }
+        let mut histo = self.histo.write().unwrap();
+        histo.record(elapsed_us).unwrap();
+        drop(histo);
+        let interval = self.inner.read().unwrap().expected_interval_us;
+        let mut corrected = self.corrected.write().unwrap();
+        match interval {
+            Some(interval) => corrected.record_correct(corrected_us, interval).unwrap(),
+            None => corrected.record(corrected_us).unwrap(),
+        }
+        drop(corrected);
+        if let Some(s) = self.specs.read().unwrap().get(spec) {
+            s.counters.record_status(status);
+            s.histo.write().unwrap().record(elapsed_us).unwrap();
+        }
+    }
+
+    // Timed out requests are kept out of the main histogram so they don't
+    // skew it; their elapsed time goes into one of its own.
+    pub fn record_timeout(&mut self, spec: usize, message: String, elapsed_us: u64) {
+        self.counters.record_timeout();
+        self.record_error(messages::ErrorKind::Timeout, message);
+        self.timeouts.write().unwrap().record(elapsed_us).unwrap();
+        if let Some(s) = self.specs.read().unwrap().get(spec) {
+            s.counters.record_timeout();
+        }
+    }
+
+    // A scenario iteration was abandoned after this step failed.
+    pub fn record_aborted(&mut self, spec: usize) {
+        self.counters.inc_aborted();
+        if let Some(s) = self.specs.read().unwrap().get(spec) {
+            s.counters.inc_aborted();
+        }
+    }
+
+    // One result per check on the spec, in order.
+    pub fn record_checks(&mut self, spec: usize, results: &[bool]) {
+        if let Some(s) = self.specs.read().unwrap().get(spec) {
+            for (c, passed) in s.checks.iter().zip(results) {
+                if *passed {
+                    c.pass.fetch_add(1, Ordering::AcqRel);
+                } else {
+                    c.fail.fetch_add(1, Ordering::AcqRel);
                 }
             }
-            None => counters.inc_fail(),
         }
-        let mut histo = self.histo.write().unwrap();
-        histo.record(elapsed_ms).unwrap();
+    }
+
+    pub fn record_response(&mut self, ttfb_us: u64, bytes_sent: u64, bytes_received: u64) {
+        self.counters.add_bytes(bytes_sent, bytes_received);
+        self.ttfb.write().unwrap().record(ttfb_us).unwrap();
+    }
+
+    pub fn record_connect_attempt(&mut self) {
+        self.connections.attempts.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn record_connection(&mut self, dns_us: u64, connect_us: u64, tls_us: Option<u64>) {
+        let c = &self.connections;
+        c.new.fetch_add(1, Ordering::AcqRel);
+        c.dns.write().unwrap().record(dns_us).unwrap();
+        c.connect.write().unwrap().record(connect_us).unwrap();
+        if let Some(tls_us) = tls_us {
+            c.tls.write().unwrap().record(tls_us).unwrap();
+        }
+    }
+
+    pub fn record_error(&mut self, kind: messages::ErrorKind, message: String) {
+        self.counters.inc_error(kind);
+        let mut messages = self.error_messages.write().unwrap();
+        let key = (kind, message);
+        if let Some(c) = messages.get_mut(&key) {
+            *c += 1;
+        } else if messages.len() < MAX_ERROR_MESSAGES {
+            messages.insert(key, 1);
+        }
+    }
+
+    // Taken on every heartbeat, keeping just enough history to cover the
+    // sliding window.
+    pub fn checkpoint(&self) {
+        let window = self.inner.read().unwrap().window;
+        let c = &self.counters;
+        let checkpoint = Checkpoint {
+            at: Instant::now(),
+            count: c.count.load(Ordering::Acquire),
+            count_1xx: c.count_1xx.load(Ordering::Acquire),
+            count_2xx: c.count_2xx.load(Ordering::Acquire),
+            count_3xx: c.count_3xx.load(Ordering::Acquire),
+            count_4xx: c.count_4xx.load(Ordering::Acquire),
+            count_5xx: c.count_5xx.load(Ordering::Acquire),
+            count_fail: c.count_fail.load(Ordering::Acquire),
+            count_timeout: c.count_timeout.load(Ordering::Acquire),
+            histo: self.histo.read().unwrap().clone(),
+        };
+        let now = checkpoint.at;
+        let mut checkpoints = self.checkpoints.write().unwrap();
+        checkpoints.push_back(checkpoint);
+        // The oldest one kept is the baseline the window is measured from.
+        while checkpoints.len() > 2 && checkpoints[1].at + window <= now {
+            checkpoints.pop_front();
+        }
     }
 
     pub fn as_message(&self) -> messages::Status {
@@ -179,12 +621,35 @@ impl Stats {
         let stdev = histo.stdev();
         let median = histo.value_at_quantile(0.5);
         let p90 = histo.value_at_quantile(0.9);
+        let percentiles = percentiles_of(&histo, stats.percentiles.iter().copied());
+        let histogram = encode_histogram(&histo).unwrap_or_default();
         drop(histo);
+        let specs = self
+            .specs
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s.as_message(i, &stats.percentiles))
+            .collect();
+        let checkpoints = self.checkpoints.read().unwrap();
+        let latest = checkpoints.back();
+        let interval = latest
+            .zip(checkpoints.iter().rev().nth(1))
+            .map(|(l, e)| l.since(e, &stats.percentiles))
+            .unwrap_or_default();
+        let window = latest
+            .zip(checkpoints.front())
+            .map(|(l, e)| l.since(e, &stats.percentiles))
+            .unwrap_or_default();
+        drop(checkpoints);
         messages::Status {
+            run_id: stats.run_id.clone(),
             hostname: None,
             socket: None,
             state: stats.state,
             elapsed: stats.elapsed.or_else(|| stats.started.map(|s| s.elapsed())),
+            stage: stats.stage,
             tasks: self.task_gauge.load(Ordering::Acquire),
             task_queue: self.task_queue.load(Ordering::Acquire),
             tasks_max: self.task_max.load(Ordering::Acquire),
@@ -194,6 +659,15 @@ impl Stats {
             stdev,
             median,
             p90,
+            percentiles,
+            histogram,
+            corrected: latency_of(&self.corrected.read().unwrap(), &stats.percentiles),
+            ttfb: latency_of(&self.ttfb.read().unwrap(), &stats.percentiles),
+            bytes_sent: counters.bytes_sent.load(Ordering::Acquire),
+            bytes_received: counters.bytes_received.load(Ordering::Acquire),
+            connections: self
+                .connections
+                .as_message(counters.count.load(Ordering::Acquire), &stats.percentiles),
             count: counters.count.load(Ordering::Acquire),
             count_1xx: counters.count_1xx.load(Ordering::Acquire),
             count_2xx: counters.count_2xx.load(Ordering::Acquire),
@@ -201,6 +675,23 @@ impl Stats {
             count_4xx: counters.count_4xx.load(Ordering::Acquire),
             count_5xx: counters.count_5xx.load(Ordering::Acquire),
             count_fail: counters.count_fail.load(Ordering::Acquire),
+            count_timeout: counters.count_timeout.load(Ordering::Acquire),
+            timeouts: latency_of(&self.timeouts.read().unwrap(), &stats.percentiles),
+            count_aborted: counters.count_aborted.load(Ordering::Acquire),
+            count_dropped: counters.count_dropped.load(Ordering::Acquire),
+            count_late: counters.count_late.load(Ordering::Acquire),
+            status_codes: self.status_codes.read().unwrap().clone(),
+            errors: counters.errors(),
+            error_messages: top_errors(self.error_messages.read().unwrap().iter().map(
+                |((kind, message), count)| messages::ErrorMessage {
+                    kind: *kind,
+                    message: message.clone(),
+                    count: *count,
+                },
+            )),
+            specs,
+            interval,
+            window,
         }
     }
 }
@@ -222,11 +713,243 @@ impl From<messages::WorkerState> for WorkerState {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct LatencySnapshot {
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stdev: f64,
+    pub percentiles: Vec<messages::Percentile>,
+    pub histogram: Option<Histogram<u64>>,
+}
+
+impl LatencySnapshot {
+    fn merge(&mut self, other: &LatencySnapshot) {
+        merge_histogram(&mut self.histogram, &other.histogram);
+    }
+
+    fn summarize(&mut self) {
+        if let Some(h) = self.histogram.as_ref() {
+            self.min = h.min();
+            self.max = h.max();
+            self.mean = h.mean();
+            self.stdev = h.stdev();
+            self.percentiles = percentiles_of(h, self.percentiles.iter().map(|p| p.percentile));
+        }
+    }
+}
+
+impl From<messages::Latency> for LatencySnapshot {
+    fn from(l: messages::Latency) -> LatencySnapshot {
+        LatencySnapshot {
+            min: l.min,
+            max: l.max,
+            mean: l.mean,
+            stdev: l.stdev,
+            percentiles: l.percentiles,
+            histogram: decode_histogram(&l.histogram).ok(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WindowSnapshot {
+    pub length: Duration,
+    pub count: u32,
+    pub count_1xx: u32,
+    pub count_2xx: u32,
+    pub count_3xx: u32,
+    pub count_4xx: u32,
+    pub count_5xx: u32,
+    pub count_fail: u32,
+    pub count_timeout: u32,
+    pub latency: LatencySnapshot,
+}
+
+impl WindowSnapshot {
+    // Adds a later stretch of time from the same worker.
+    fn extend(&mut self, later: &WindowSnapshot) {
+        let length = self.length + later.length;
+        self.merge(later);
+        self.length = length;
+    }
+
+    // Adds the same stretch of time from another worker.
+    fn merge(&mut self, other: &WindowSnapshot) {
+        self.length = self.length.max(other.length);
+        self.count += other.count;
+        self.count_1xx += other.count_1xx;
+        self.count_2xx += other.count_2xx;
+        self.count_3xx += other.count_3xx;
+        self.count_4xx += other.count_4xx;
+        self.count_5xx += other.count_5xx;
+        self.count_fail += other.count_fail;
+        self.count_timeout += other.count_timeout;
+        self.latency.merge(&other.latency);
+    }
+}
+
+impl From<messages::Window> for WindowSnapshot {
+    fn from(w: messages::Window) -> WindowSnapshot {
+        WindowSnapshot {
+            length: w.length,
+            count: w.count,
+            count_1xx: w.count_1xx,
+            count_2xx: w.count_2xx,
+            count_3xx: w.count_3xx,
+            count_4xx: w.count_4xx,
+            count_5xx: w.count_5xx,
+            count_fail: w.count_fail,
+            count_timeout: w.count_timeout,
+            latency: w.latency.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionsSnapshot {
+    pub new: u32,
+    pub reused: u32,
+    pub dns: LatencySnapshot,
+    pub connect: LatencySnapshot,
+    pub tls: LatencySnapshot,
+}
+
+impl ConnectionsSnapshot {
+    fn merge(&mut self, other: &ConnectionsSnapshot) {
+        self.new += other.new;
+        self.reused += other.reused;
+        self.dns.merge(&other.dns);
+        self.connect.merge(&other.connect);
+        self.tls.merge(&other.tls);
+    }
+
+    fn summarize(&mut self) {
+        self.dns.summarize();
+        self.connect.summarize();
+        self.tls.summarize();
+    }
+
+    fn release_histograms(&mut self) {
+        self.dns.histogram = None;
+        self.connect.histogram = None;
+        self.tls.histogram = None;
+    }
+}
+
+impl From<messages::Connections> for ConnectionsSnapshot {
+    fn from(c: messages::Connections) -> ConnectionsSnapshot {
+        ConnectionsSnapshot {
+            new: c.new,
+            reused: c.reused,
+            dns: c.dns.into(),
+            connect: c.connect.into(),
+            tls: c.tls.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpecSnapshot {
+    pub index: usize,
+    pub name: Option<String>,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stdev: f64,
+    pub percentiles: Vec<messages::Percentile>,
+    pub histogram: Option<Histogram<u64>>,
+    pub count: u32,
+    pub count_1xx: u32,
+    pub count_2xx: u32,
+    pub count_3xx: u32,
+    pub count_4xx: u32,
+    pub count_5xx: u32,
+    pub count_fail: u32,
+    pub count_timeout: u32,
+    pub count_aborted: u32,
+    pub checks: Vec<messages::CheckStatus>,
+    pub planned_share: f64,
+}
+
+impl SpecSnapshot {
+    fn merge(&mut self, other: &SpecSnapshot) {
+        merge_histogram(&mut self.histogram, &other.histogram);
+        self.count += other.count;
+        self.count_1xx += other.count_1xx;
+        self.count_2xx += other.count_2xx;
+        self.count_3xx += other.count_3xx;
+        self.count_4xx += other.count_4xx;
+        self.count_5xx += other.count_5xx;
+        self.count_fail += other.count_fail;
+        self.count_timeout += other.count_timeout;
+        self.count_aborted += other.count_aborted;
+        for (c, o) in self.checks.iter_mut().zip(other.checks.iter()) {
+            c.pass += o.pass;
+            c.fail += o.fail;
+        }
+    }
+
+    fn summarize(&mut self) {
+        if let Some(h) = self.histogram.as_ref() {
+            self.min = h.min();
+            self.max = h.max();
+            self.mean = h.mean();
+            self.stdev = h.stdev();
+            self.percentiles = percentiles_of(h, self.percentiles.iter().map(|p| p.percentile));
+        }
+    }
+}
+
+impl From<messages::SpecStatus> for SpecSnapshot {
+    fn from(s: messages::SpecStatus) -> SpecSnapshot {
+        SpecSnapshot {
+            index: s.index,
+            name: s.name,
+            min: s.min,
+            max: s.max,
+            mean: s.mean,
+            stdev: s.stdev,
+            percentiles: s.percentiles,
+            histogram: decode_histogram(&s.histogram).ok(),
+            count: s.count,
+            count_1xx: s.count_1xx,
+            count_2xx: s.count_2xx,
+            count_3xx: s.count_3xx,
+            count_4xx: s.count_4xx,
+            count_5xx: s.count_5xx,
+            count_fail: s.count_fail,
+            count_timeout: s.count_timeout,
+            count_aborted: s.count_aborted,
+            checks: s.checks,
+            planned_share: s.planned_share,
+        }
+    }
+}
+
+fn merge_histogram(into: &mut Option<Histogram<u64>>, from: &Option<Histogram<u64>>) {
+    match (into.as_mut(), from.as_ref()) {
+        (Some(a), Some(b)) => {
+            a.auto(true);
+            let _ = a.add(b);
+        }
+        (None, Some(b)) => {
+            let mut h = b.clone();
+            h.auto(true);
+            *into = Some(h);
+        }
+        _ => {}
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Snapshot {
+    #[allow(dead_code)]
     pub timestamp: SystemTime,
+    pub run_id: Option<String>,
     pub state: WorkerState,
     pub elapsed: Option<Duration>,
+    pub stage: Option<messages::ActiveStage>,
     pub tasks: u32,
     pub task_queue: u32,
     pub tasks_max: u32,
@@ -236,6 +959,13 @@ pub struct Snapshot {
     pub stdev: f64,
     pub median: u64,
     pub p90: u64,
+    pub percentiles: Vec<messages::Percentile>,
+    pub histogram: Option<Histogram<u64>>,
+    pub corrected: LatencySnapshot,
+    pub ttfb: LatencySnapshot,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub connections: ConnectionsSnapshot,
     pub count: u32,
     pub count_1xx: u32,
     pub count_2xx: u32,
@@ -243,14 +973,138 @@ pub struct Snapshot {
     pub count_4xx: u32,
     pub count_5xx: u32,
     pub count_fail: u32,
+    pub count_timeout: u32,
+    pub timeouts: LatencySnapshot,
+    pub count_aborted: u32,
+    pub count_dropped: u32,
+    pub count_late: u32,
+    pub status_codes: BTreeMap<u16, u32>,
+    pub errors: BTreeMap<messages::ErrorKind, u32>,
+    pub error_messages: Vec<messages::ErrorMessage>,
+    pub specs: Vec<SpecSnapshot>,
+    pub interval: WindowSnapshot,
+    pub window: WindowSnapshot,
+}
+
+impl Snapshot {
+    // Combines the latest snapshots of several workers into one describing
+    // the whole cluster. Latency figures are recomputed from the merged
+    // histogram rather than averaged.
+    pub fn aggregate<'a, I>(snapshots: I) -> Option<Snapshot>
+    where
+        I: Iterator<Item = &'a Snapshot>,
+    {
+        let mut agg: Option<Snapshot> = None;
+        for s in snapshots {
+            match agg.as_mut() {
+                Some(a) => a.merge(s),
+                None => agg = Some(s.clone()),
+            }
+        }
+        if let Some(a) = agg.as_mut() {
+            a.timestamp = SystemTime::now();
+            if let Some(h) = a.histogram.as_ref() {
+                a.min = h.min();
+                a.max = h.max();
+                a.mean = h.mean();
+                a.stdev = h.stdev();
+                a.median = h.value_at_quantile(0.5);
+                a.p90 = h.value_at_quantile(0.9);
+                a.percentiles = percentiles_of(h, a.percentiles.iter().map(|p| p.percentile));
+            }
+            a.corrected.summarize();
+            a.ttfb.summarize();
+            a.timeouts.summarize();
+            a.connections.summarize();
+            a.interval.latency.summarize();
+            a.window.latency.summarize();
+            for spec in a.specs.iter_mut() {
+                spec.summarize();
+            }
+        }
+        agg
+    }
+
+    fn merge(&mut self, other: &Snapshot) {
+        if other.state == WorkerState::Busy {
+            self.state = WorkerState::Busy;
+        }
+        if self.run_id != other.run_id {
+            self.run_id = None;
+        }
+        self.elapsed = match (self.elapsed, other.elapsed) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        self.stage = match (self.stage, other.stage) {
+            (Some(a), Some(b)) => Some(messages::ActiveStage {
+                index: a.index.max(b.index),
+                target: a.target + b.target,
+                elapsed: a.elapsed.max(b.elapsed),
+            }),
+            (a, b) => a.or(b),
+        };
+        self.tasks += other.tasks;
+        self.task_queue += other.task_queue;
+        self.tasks_max += other.tasks_max;
+        merge_histogram(&mut self.histogram, &other.histogram);
+        self.corrected.merge(&other.corrected);
+        self.ttfb.merge(&other.ttfb);
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.connections.merge(&other.connections);
+        self.interval.merge(&other.interval);
+        self.window.merge(&other.window);
+        for spec in other.specs.iter() {
+            match self.specs.iter_mut().find(|s| s.index == spec.index) {
+                Some(s) => s.merge(spec),
+                None => self.specs.push(spec.clone()),
+            }
+        }
+        self.count += other.count;
+        self.count_1xx += other.count_1xx;
+        self.count_2xx += other.count_2xx;
+        self.count_3xx += other.count_3xx;
+        self.count_4xx += other.count_4xx;
+        self.count_5xx += other.count_5xx;
+        self.count_fail += other.count_fail;
+        self.count_timeout += other.count_timeout;
+        self.timeouts.merge(&other.timeouts);
+        self.count_aborted += other.count_aborted;
+        self.count_dropped += other.count_dropped;
+        self.count_late += other.count_late;
+        for (code, count) in other.status_codes.iter() {
+            *self.status_codes.entry(*code).or_insert(0) += count;
+        }
+        for (kind, count) in other.errors.iter() {
+            *self.errors.entry(*kind).or_insert(0) += count;
+        }
+        let mut messages: HashMap<(messages::ErrorKind, String), u32> = HashMap::new();
+        for m in self
+            .error_messages
+            .drain(..)
+            .chain(other.error_messages.iter().cloned())
+        {
+            *messages.entry((m.kind, m.message)).or_insert(0) += m.count;
+        }
+        self.error_messages = top_errors(messages.into_iter().map(|((kind, message), count)| {
+            messages::ErrorMessage {
+                kind,
+                message,
+                count,
+            }
+        }));
+    }
 }
 
 impl From<messages::Status> for Snapshot {
     fn from(s: messages::Status) -> Snapshot {
         Snapshot {
             timestamp: SystemTime::now(),
+            run_id: s.run_id,
             state: s.state.into(),
             elapsed: s.elapsed,
+            stage: s.stage,
             tasks: s.tasks,
             task_queue: s.task_queue,
             tasks_max: s.tasks_max,
@@ -260,6 +1114,13 @@ impl From<messages::Status> for Snapshot {
             stdev: s.stdev,
             median: s.median,
             p90: s.p90,
+            percentiles: s.percentiles,
+            histogram: decode_histogram(&s.histogram).ok(),
+            corrected: s.corrected.into(),
+            ttfb: s.ttfb.into(),
+            bytes_sent: s.bytes_sent,
+            bytes_received: s.bytes_received,
+            connections: s.connections.into(),
             count: s.count,
             count_1xx: s.count_1xx,
             count_2xx: s.count_2xx,
@@ -267,6 +1128,17 @@ impl From<messages::Status> for Snapshot {
             count_4xx: s.count_4xx,
             count_5xx: s.count_5xx,
             count_fail: s.count_fail,
+            count_timeout: s.count_timeout,
+            timeouts: s.timeouts.into(),
+            count_aborted: s.count_aborted,
+            count_dropped: s.count_dropped,
+            count_late: s.count_late,
+            status_codes: s.status_codes,
+            errors: s.errors,
+            error_messages: s.error_messages,
+            specs: s.specs.into_iter().map(SpecSnapshot::from).collect(),
+            interval: s.interval.into(),
+            window: s.window.into(),
         }
     }
 }
@@ -277,9 +1149,61 @@ pub struct Status {
     pub hostname: Option<String>,
     pub socket: SocketAddr,
     pub state: WorkerState,
+    #[allow(dead_code)]
     pub connect_time: SystemTime,
     pub disconnect_time: Option<SystemTime>,
     pub snapshots: VecDeque<Snapshot>,
+    // Interval stats of the current run, oldest first.
+    pub history: VecDeque<HistoryPoint>,
+    history_run: Option<String>,
+}
+
+// One heartbeat's interval stats. The histogram stays encoded until the
+// history is asked for, which keeps these small.
+#[derive(Debug, Clone)]
+pub struct HistoryPoint {
+    pub timestamp: SystemTime,
+    pub elapsed: Duration,
+    pub interval: messages::Window,
+}
+
+// Everything that happened in `resolution` worth of the run, starting at
+// `elapsed`.
+#[derive(Debug, Clone)]
+pub struct HistoryBucket {
+    pub elapsed: Duration,
+    pub timestamp: SystemTime,
+    pub window: WindowSnapshot,
+}
+
+fn bucket_start(elapsed: Duration, resolution: Duration) -> Duration {
+    let ms = resolution.as_millis().max(1);
+    Duration::from_millis((elapsed.as_millis() / ms * ms) as u64)
+}
+
+fn downsample(points: &VecDeque<HistoryPoint>, resolution: Duration) -> Vec<HistoryBucket> {
+    let mut buckets: Vec<HistoryBucket> = Vec::new();
+    for p in points.iter() {
+        let start = bucket_start(p.elapsed, resolution);
+        let window = WindowSnapshot::from(p.interval.clone());
+        match buckets.last_mut() {
+            Some(b) if b.elapsed == start => {
+                b.window.extend(&window);
+                b.timestamp = p.timestamp;
+            }
+            _ => buckets.push(HistoryBucket {
+                elapsed: start,
+                timestamp: p.timestamp,
+                window,
+            }),
+        }
+    }
+    buckets
+}
+
+pub struct History {
+    pub cluster: Vec<HistoryBucket>,
+    pub workers: Vec<(u32, Option<String>, Vec<HistoryBucket>)>,
 }
 
 impl Status {
@@ -292,12 +1216,57 @@ impl Status {
             connect_time: SystemTime::now(),
             disconnect_time: None,
             snapshots: VecDeque::new(),
+            history: VecDeque::new(),
+            history_run: None,
         }
     }
 
-    pub fn record(&mut self, status: messages::Status) {
+    // Points older than `retention`, measured back from the newest, are
+    // dropped.
+    fn record_history(&mut self, status: &messages::Status, retention: Duration) {
+        let (run_id, elapsed) = match (status.run_id.as_ref(), status.elapsed) {
+            (Some(r), Some(e)) => (r, e),
+            _ => return,
+        };
+        if self.history_run.as_ref() != Some(run_id) {
+            self.history.clear();
+            self.history_run = Some(run_id.clone());
+        }
+        // Once the run is over only the final interval is worth keeping.
+        if status.state != messages::WorkerState::Busy && status.interval.count == 0 {
+            return;
+        }
+        self.history.push_back(HistoryPoint {
+            timestamp: SystemTime::now(),
+            elapsed,
+            interval: status.interval.clone(),
+        });
+        while self
+            .history
+            .front()
+            .is_some_and(|p| p.elapsed + retention < elapsed)
+        {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn record(&mut self, status: messages::Status, retention: Duration) {
         self.state = status.state.into();
         self.hostname = status.hostname.clone();
+        self.record_history(&status, retention);
+        // Only the latest histogram is ever read, so older ones are released.
+        if let Some(prev) = self.snapshots.front_mut() {
+            prev.histogram = None;
+            prev.corrected.histogram = None;
+            prev.ttfb.histogram = None;
+            prev.timeouts.histogram = None;
+            prev.connections.release_histograms();
+            prev.interval.latency.histogram = None;
+            prev.window.latency.histogram = None;
+            for spec in prev.specs.iter_mut() {
+                spec.histogram = None;
+            }
+        }
         self.snapshots.push_front(status.into());
         self.snapshots.truncate(100);
     }
@@ -312,10 +1281,73 @@ impl Status {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RunState {
+    Running,
+    Complete,
+    Stopped,
+}
+
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub id: String,
+    pub state: RunState,
+    pub started: SystemTime,
+    pub finished: Option<SystemTime>,
+    // The workers taking part. Fixed when the run starts unless the run can
+    // take on workers that connect later; see StatsCollector::shard.
+    shards: HashMap<u32, messages::Shard>,
+}
+
+impl Run {
+    fn start(id: &str, workers: &[u32]) -> Run {
+        let count = workers.len() as u32;
+        Run {
+            id: id.to_string(),
+            state: RunState::Running,
+            started: SystemTime::now(),
+            finished: None,
+            shards: workers
+                .iter()
+                .enumerate()
+                .map(|(index, id)| {
+                    (
+                        *id,
+                        messages::Shard {
+                            index: index as u32,
+                            count,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn finish(&mut self, state: RunState) {
+        if self.state == RunState::Running {
+            self.state = state;
+            self.finished = Some(SystemTime::now());
+        }
+    }
+}
+
+// Sent to anyone following the cluster live, once the change has been applied.
+#[derive(Debug, Clone, Copy)]
+pub enum StatsEvent {
+    Connected(u32),
+    Updated,
+    Disconnected(u32),
+}
+
+pub const DEFAULT_HISTORY_RETENTION: Duration = Duration::from_secs(3600);
+
 #[derive(Debug, Clone)]
 pub struct StatsCollector {
     stats: Arc<RwLock<HashMap<u32, Status>>>,
     id_counter: Arc<AtomicU32>,
+    run: Arc<RwLock<Option<Run>>>,
+    history_retention: Arc<RwLock<Duration>>,
+    events: broadcast::Sender<StatsEvent>,
 }
 
 impl StatsCollector {
@@ -323,14 +1355,28 @@ impl StatsCollector {
         StatsCollector {
             stats: Arc::new(RwLock::new(HashMap::new())),
             id_counter: Arc::new(AtomicU32::new(0)),
+            run: Arc::new(RwLock::new(None)),
+            history_retention: Arc::new(RwLock::new(DEFAULT_HISTORY_RETENTION)),
+            events: broadcast::channel(100).0,
         }
     }
 
+    pub fn subscribe(&self) -> broadcast::Receiver<StatsEvent> {
+        self.events.subscribe()
+    }
+
+    // Nobody listening is fine.
+    fn notify(&self, event: StatsEvent) {
+        let _ = self.events.send(event);
+    }
+
     pub fn connect(&self, socket: SocketAddr) -> u32 {
         let rc = self.stats.clone();
         let mut map = rc.write().unwrap();
         let id = self.id_counter.fetch_add(1, Ordering::SeqCst);
         map.insert(id, Status::connect(id, socket, None));
+        drop(map);
+        self.notify(StatsEvent::Connected(id));
         id
     }
 
@@ -338,16 +1384,137 @@ impl StatsCollector {
         let rc = self.stats.clone();
         let mut map = rc.write().unwrap();
         if let Some(s) = map.get_mut(&id) {
-            s.record(stats);
+            s.record(stats, *self.history_retention.read().unwrap());
         } else {
             return Err(Error::msg(format!(
                 "ID {} not found in current clients",
                 id
             )));
         }
+        let mut run = self.run.write().unwrap();
+        if let Some(run) = run.as_mut() {
+            let finished = map
+                .values()
+                .filter(|s| !s.is_disconnected() && run.shards.contains_key(&s.id))
+                .all(|s| {
+                    s.snapshots.front().is_some_and(|snap| {
+                        snap.state == WorkerState::Idle && snap.run_id.as_ref() == Some(&run.id)
+                    })
+                });
+            if finished {
+                run.finish(RunState::Complete);
+            }
+        }
+        drop(run);
+        drop(map);
+        self.notify(StatsEvent::Updated);
         Ok(())
     }
 
+    pub fn start_run(&self, id: &str, history_retention: Duration) {
+        let mut workers: Vec<u32> = self
+            .stats
+            .read()
+            .unwrap()
+            .values()
+            .filter(|s| !s.is_disconnected())
+            .map(|s| s.id)
+            .collect();
+        workers.sort_unstable();
+        *self.run.write().unwrap() = Some(Run::start(id, &workers));
+        *self.history_retention.write().unwrap() = history_retention;
+    }
+
+    pub fn stop_run(&self) {
+        if let Some(run) = self.run.write().unwrap().as_mut() {
+            run.finish(RunState::Stopped);
+        }
+    }
+
+    pub fn clear_run(&self) {
+        *self.run.write().unwrap() = None;
+    }
+
+    pub fn run(&self) -> Option<Run> {
+        self.run.read().unwrap().clone()
+    }
+
+    pub fn aggregate(&self) -> Option<Snapshot> {
+        self.with_stats(|map| {
+            Snapshot::aggregate(
+                map.values()
+                    .filter(|s| !s.is_disconnected())
+                    .filter_map(|s| s.snapshots.front()),
+            )
+        })
+    }
+
+    // Each worker's history and the cluster's, in buckets of `resolution`.
+    // Workers are lined up by how far into the run they were.
+    pub fn history(&self, resolution: Duration) -> History {
+        self.with_stats(|map| {
+            let mut ids: Vec<&u32> = map.keys().collect();
+            ids.sort_unstable();
+            let workers: Vec<_> = ids
+                .into_iter()
+                .map(|id| {
+                    let s = &map[id];
+                    (s, downsample(&s.history, resolution))
+                })
+                .collect();
+            let mut cluster: BTreeMap<Duration, HistoryBucket> = BTreeMap::new();
+            for b in workers
+                .iter()
+                .filter(|(s, _)| !s.is_disconnected())
+                .flat_map(|(_, buckets)| buckets.iter())
+            {
+                match cluster.get_mut(&b.elapsed) {
+                    Some(c) => {
+                        c.window.merge(&b.window);
+                        c.timestamp = c.timestamp.max(b.timestamp);
+                    }
+                    None => {
+                        cluster.insert(b.elapsed, b.clone());
+                    }
+                }
+            }
+            let mut cluster: Vec<HistoryBucket> = cluster.into_values().collect();
+            let mut workers: Vec<_> = workers
+                .into_iter()
+                .map(|(s, buckets)| (s.id, s.hostname.clone(), buckets))
+                .collect();
+            for b in cluster.iter_mut().chain(
+                workers
+                    .iter_mut()
+                    .flat_map(|(_, _, buckets)| buckets.iter_mut()),
+            ) {
+                b.window.latency.summarize();
+            }
+            History { cluster, workers }
+        })
+    }
+
+    // The worker's share of the run. A worker that connects while the run is
+    // going joins it, unless the run splits a total between the workers it
+    // started with, in which case it gets None and sits the run out.
+    pub fn shard(&self, spec: &messages::RunSpec, id: u32) -> Option<messages::Shard> {
+        let mut run = self.run.write().unwrap();
+        let run = run.as_mut().filter(|r| r.id == spec.run_id)?;
+        if let Some(shard) = run.shards.get(&id) {
+            return Some(*shard);
+        }
+        if spec.fixed_split() || run.state != RunState::Running {
+            return None;
+        }
+        let index = run.shards.len() as u32;
+        let shard = messages::Shard {
+            index,
+            count: index + 1,
+        };
+        run.shards.insert(id, shard);
+        Some(shard)
+    }
+
     pub fn prune_disconnected(&self) {
         let rc = self.stats.clone();
         let mut map = rc.write().unwrap();
@@ -360,6 +1527,8 @@ impl StatsCollector {
         if let Some(s) = map.get_mut(&id) {
             s.disconnect();
         }
+        drop(map);
+        self.notify(StatsEvent::Disconnected(id));
     }
 
     pub fn with_stats<F, V>(&self, f: F) -> V
@@ -0,0 +1,497 @@
+use crate::{feeder::FeederState, messages};
+use anyhow::{anyhow, bail, Result};
+use rand::{distributions::Alphanumeric, seq::SliceRandom, Rng};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Values substituted into `{{...}}` placeholders in urls, header values and
+// bodies. Templates are parsed once per run and rendered for every request:
+//
+//   {{uuid}}                   random v4 uuid
+//   {{random_int 1 1000}}      integer in the inclusive range
+//   {{random_choice a,b,c}}    one of the comma separated values
+//   {{random_string 16}}       alphanumeric string of the given length
+//   {{seq}} / {{seq name}}     per-worker counter, one value per request
+//   {{worker_id}}              index of this worker in the run
+//   {{timestamp}}              unix time in seconds
+//   {{timestamp_ms}}           unix time in milliseconds
+//   {{name}}                   a run variable
+//   {{feeder.column}}          a column of the feeder's current row
+//
+// `{{{{` is a literal `{{`.
+// Longest {{random_string n}}; each request allocates the whole string.
+const MAX_RANDOM_STRING: usize = 65536;
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Uuid,
+    RandomInt(i64, i64),
+    RandomChoice(Vec<String>),
+    RandomString(usize),
+    Seq(usize),
+    WorkerId,
+    Timestamp,
+    TimestampMs,
+    Var(String),
+    Feed(usize, usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+// Named sequence counters, shared by every template in a run.
+#[derive(Debug, Default)]
+pub struct Sequences {
+    names: Vec<String>,
+    counters: Vec<AtomicU64>,
+}
+
+impl Sequences {
+    pub fn new() -> Sequences {
+        Sequences::default()
+    }
+
+    fn index_of(&mut self, name: &str) -> usize {
+        if let Some(i) = self.names.iter().position(|n| n == name) {
+            return i;
+        }
+        self.names.push(name.to_string());
+        self.counters.push(AtomicU64::new(0));
+        self.names.len() - 1
+    }
+}
+
+// Everything a template needs at render time. Sequence values and feeder rows
+// are taken once per scope, so a counter or column used in both the url and
+// the body of a request renders the same value in each.
+pub struct Scope<'a> {
+    worker_id: u32,
+    sequences: &'a Sequences,
+    taken: Vec<Option<u64>>,
+    vars: &'a HashMap<String, String>,
+    extracted: HashMap<String, String>,
+    feeders: &'a [FeederState],
+    rows: Vec<Option<usize>>,
+}
+
+impl<'a> Scope<'a> {
+    pub fn new(
+        worker_id: u32,
+        sequences: &'a Sequences,
+        vars: &'a HashMap<String, String>,
+        feeders: &'a [FeederState],
+    ) -> Scope<'a> {
+        Scope {
+            worker_id,
+            sequences,
+            taken: vec![None; sequences.counters.len()],
+            vars,
+            extracted: HashMap::new(),
+            feeders,
+            rows: vec![None; feeders.len()],
+        }
+    }
+
+    fn seq(&mut self, index: usize) -> u64 {
+        let sequences = self.sequences;
+        *self.taken[index]
+            .get_or_insert_with(|| sequences.counters[index].fetch_add(1, Ordering::AcqRel))
+    }
+
+    // Carries variables extracted in earlier iterations into this one.
+    pub fn with_extracted(mut self, extracted: HashMap<String, String>) -> Scope<'a> {
+        self.extracted = extracted;
+        self
+    }
+
+    pub fn into_extracted(self) -> HashMap<String, String> {
+        self.extracted
+    }
+
+    // Values extracted by earlier scenario steps shadow run variables.
+    pub fn set(&mut self, name: &str, value: String) {
+        self.extracted.insert(name.to_string(), value);
+    }
+
+    fn var(&self, name: &str) -> &str {
+        self.extracted
+            .get(name)
+            .or_else(|| self.vars.get(name))
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+
+    fn row(&mut self, feeder: usize) -> Option<usize> {
+        if self.rows[feeder].is_none() {
+            self.rows[feeder] = self.feeders[feeder].take();
+        }
+        self.rows[feeder]
+    }
+
+    // Draws a row from each of the feeders up front. False if any of them has
+    // run dry.
+    pub fn take_rows(&mut self, feeders: &[usize]) -> bool {
+        feeders.iter().all(|f| self.row(*f).is_some())
+    }
+}
+
+// Names a template may refer to, collected while parsing a run.
+struct Parser<'a> {
+    vars: Vec<&'a str>,
+    feeders: &'a [messages::Feeder],
+    sequences: Sequences,
+}
+
+impl<'a> Parser<'a> {
+    fn feed(&self, expr: &str) -> Option<Part> {
+        let (name, column) = expr.split_once('.')?;
+        let (f, feeder) = self
+            .feeders
+            .iter()
+            .enumerate()
+            .find(|(_, f)| f.name == name)?;
+        let c = feeder.columns.iter().position(|c| c == column)?;
+        Some(Part::Feed(f, c))
+    }
+}
+
+fn parse_part(expr: &str, parser: &mut Parser) -> Result<Part> {
+    let expr = expr.trim();
+    let (name, args) = match expr.find(char::is_whitespace) {
+        Some(i) => (&expr[..i], expr[i..].trim()),
+        None => (expr, ""),
+    };
+    let no_args = |part: Part| {
+        if args.is_empty() {
+            Ok(part)
+        } else {
+            Err(anyhow!("{} takes no arguments", name))
+        }
+    };
+    match name {
+        "uuid" => no_args(Part::Uuid),
+        "worker_id" => no_args(Part::WorkerId),
+        "timestamp" => no_args(Part::Timestamp),
+        "timestamp_ms" => no_args(Part::TimestampMs),
+        "seq" => Ok(Part::Seq(parser.sequences.index_of(args))),
+        "random_int" => {
+            let bounds = args
+                .split_whitespace()
+                .map(|a| a.parse::<i64>())
+                .collect::<Result<Vec<_>, _>>()?;
+            match bounds[..] {
+                [min, max] if min <= max => Ok(Part::RandomInt(min, max)),
+                _ => bail!("random_int takes a minimum and maximum"),
+            }
+        }
+        "random_choice" => {
+            let choices: Vec<String> = args.split(',').map(|c| c.trim().to_string()).collect();
+            if args.is_empty() {
+                bail!("random_choice needs at least one value");
+            }
+            Ok(Part::RandomChoice(choices))
+        }
+        "random_string" => {
+            let len: usize = args.parse()?;
+            if len > MAX_RANDOM_STRING {
+                bail!(
+                    "random_string is limited to {} characters",
+                    MAX_RANDOM_STRING
+                );
+            }
+            Ok(Part::RandomString(len))
+        }
+        var if args.is_empty() && parser.vars.contains(&var) => Ok(Part::Var(var.to_string())),
+        _ if args.is_empty() => parser
+            .feed(expr)
+            .ok_or_else(|| anyhow!("unknown template expression {{{{{}}}}}", expr)),
+        _ => bail!("unknown template expression {{{{{}}}}}", expr),
+    }
+}
+
+impl Template {
+    fn parse(s: &str, parser: &mut Parser) -> Result<Template> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("{{") {
+            literal.push_str(&rest[..start]);
+            if rest[start..].starts_with("{{{{") {
+                literal.push_str("{{");
+                rest = &rest[start + 4..];
+                continue;
+            }
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| anyhow!("unclosed {{{{ in {:?}", s))?;
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(parse_part(&rest[start + 2..start + end], parser)?);
+            rest = &rest[start + end + 2..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    pub fn literal(s: &str) -> Template {
+        Template {
+            parts: vec![Part::Literal(s.to_string())],
+        }
+    }
+
+    pub fn render(&self, scope: &mut Scope) -> String {
+        let mut out = String::new();
+        let mut rng = rand::thread_rng();
+        for part in self.parts.iter() {
+            let _ = match part {
+                Part::Literal(s) => write!(out, "{}", s),
+                Part::Uuid => write!(out, "{}", uuid::Uuid::new_v4()),
+                Part::RandomInt(min, max) => write!(out, "{}", rng.gen_range(*min..=*max)),
+                Part::RandomChoice(choices) => {
+                    write!(out, "{}", choices.choose(&mut rng).unwrap())
+                }
+                Part::RandomString(len) => {
+                    out.extend(
+                        (&mut rng)
+                            .sample_iter(Alphanumeric)
+                            .take(*len)
+                            .map(char::from),
+                    );
+                    Ok(())
+                }
+                Part::Seq(i) => write!(out, "{}", scope.seq(*i)),
+                Part::WorkerId => write!(out, "{}", scope.worker_id),
+                Part::Timestamp => write!(out, "{}", unix_time().as_secs()),
+                Part::TimestampMs => write!(out, "{}", unix_time().as_millis()),
+                Part::Var(name) => write!(out, "{}", scope.var(name)),
+                Part::Feed(f, c) => match scope.row(*f) {
+                    Some(row) => write!(out, "{}", scope.feeders[*f].value(row, *c)),
+                    None => Ok(()),
+                },
+            };
+        }
+        out
+    }
+}
+
+fn unix_time() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// The templated parts of a RequestSpec.
+#[derive(Debug, Clone)]
+pub struct RequestTemplate {
+    pub url: Template,
+    pub headers: Vec<(String, Template)>,
+    pub body: Option<Template>,
+    // Feeders referenced anywhere in the request.
+    pub feeders: Vec<usize>,
+}
+
+impl RequestTemplate {
+    fn parse(spec: &messages::RequestSpec, parser: &mut Parser) -> Result<RequestTemplate> {
+        let mut headers = Vec::new();
+        for (name, value) in spec.headers.iter() {
+            headers.push((name.clone(), Template::parse(value, parser)?));
+        }
+        let url = Template::parse(&spec.url, parser)?;
+        let body = match spec.body {
+            Some(ref b) => Some(Template::parse(b, parser)?),
+            None => None,
+        };
+        let mut feeders: Vec<usize> = std::iter::once(&url)
+            .chain(headers.iter().map(|(_, t)| t))
+            .chain(body.iter())
+            .flat_map(|t| t.parts.iter())
+            .filter_map(|p| match p {
+                Part::Feed(f, _) => Some(*f),
+                _ => None,
+            })
+            .collect();
+        feeders.sort_unstable();
+        feeders.dedup();
+        Ok(RequestTemplate {
+            url,
+            headers,
+            body,
+            feeders,
+        })
+    }
+
+    pub fn literal(spec: &messages::RequestSpec) -> RequestTemplate {
+        RequestTemplate {
+            url: Template::literal(&spec.url),
+            headers: spec
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), Template::literal(value)))
+                .collect(),
+            body: spec.body.as_deref().map(Template::literal),
+            feeders: Vec::new(),
+        }
+    }
+}
+
+// Parses the templates of every request in a run, in order.
+pub fn parse_run(spec: &messages::RunSpec) -> Result<(Vec<RequestTemplate>, Sequences)> {
+    for (i, f) in spec.feeders.iter().enumerate() {
+        if spec.feeders[..i].iter().any(|other| other.name == f.name) {
+            bail!("duplicate feeder {}", f.name);
+        }
+    }
+    let mut vars: Vec<&str> = spec.variables.keys().map(String::as_str).collect();
+    if let messages::AttackStrategy::Scenario = spec.strategy {
+        vars.extend(
+            spec.requests
+                .iter()
+                .flat_map(|r| r.extract.iter())
+                .map(|e| e.name.as_str()),
+        );
+    }
+    let mut parser = Parser {
+        vars,
+        feeders: &spec.feeders,
+        sequences: Sequences::new(),
+    };
+    let templates = spec
+        .requests
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            RequestTemplate::parse(r, &mut parser).map_err(|e| anyhow!("request {}: {}", i, e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((templates, parser.sequences))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feeder() -> messages::Feeder {
+        messages::Feeder {
+            name: "users".to_string(),
+            columns: vec!["id".to_string(), "name".to_string()],
+            rows: vec![
+                vec!["1".to_string(), "alice".to_string()],
+                vec!["2".to_string(), "bob".to_string()],
+            ],
+            order: messages::FeedOrder::Sequential,
+            partition: false,
+        }
+    }
+
+    fn parse(s: &str, feeders: &[messages::Feeder]) -> Result<(Template, Sequences)> {
+        let mut parser = Parser {
+            vars: vec!["host"],
+            feeders,
+            sequences: Sequences::new(),
+        };
+        let t = Template::parse(s, &mut parser)?;
+        Ok((t, parser.sequences))
+    }
+
+    fn render_all(
+        templates: &[&Template],
+        sequences: &Sequences,
+        feeders: &[FeederState],
+    ) -> Vec<String> {
+        let mut vars = HashMap::new();
+        vars.insert("host".to_string(), "example.com".to_string());
+        let mut scope = Scope::new(3, sequences, &vars, feeders);
+        templates.iter().map(|t| t.render(&mut scope)).collect()
+    }
+
+    #[test]
+    fn unclosed_placeholder_is_an_error() {
+        let err = parse("/users/{{uuid", &[]).unwrap_err();
+        assert!(err.to_string().contains("unclosed"));
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let (t, seqs) = parse("{\"a\": {{{{x}}, \"b\": {{worker_id}}}", &[]).unwrap();
+        assert_eq!(
+            render_all(&[&t], &seqs, &[]),
+            vec!["{\"a\": {{x}}, \"b\": 3}"]
+        );
+        let (t, seqs) = parse("{{{{{{{{", &[]).unwrap();
+        assert_eq!(render_all(&[&t], &seqs, &[]), vec!["{{{{"]);
+    }
+
+    #[test]
+    fn random_string_length_is_capped() {
+        assert!(parse("{{random_string 100000000000}}", &[]).is_err());
+        let (t, seqs) = parse("{{random_string 16}}", &[]).unwrap();
+        assert_eq!(render_all(&[&t], &seqs, &[])[0].len(), 16);
+    }
+
+    #[test]
+    fn unknown_expressions_are_errors() {
+        assert!(parse("{{nope}}", &[]).is_err());
+        assert!(parse("{{uuid 4}}", &[]).is_err());
+        assert!(parse("{{users.missing}}", &[feeder()]).is_err());
+        assert!(parse("{{orders.id}}", &[feeder()]).is_err());
+    }
+
+    #[test]
+    fn random_int_bounds() {
+        assert!(parse("{{random_int 5 1}}", &[]).is_err());
+        assert!(parse("{{random_int 5}}", &[]).is_err());
+        assert!(parse("{{random_int a b}}", &[]).is_err());
+        let (t, seqs) = parse("{{random_int -2 2}}", &[]).unwrap();
+        for _ in 0..100 {
+            let v: i64 = render_all(&[&t], &seqs, &[])[0].parse().unwrap();
+            assert!((-2..=2).contains(&v));
+        }
+        let (t, seqs) = parse("{{random_int 7 7}}", &[]).unwrap();
+        assert_eq!(render_all(&[&t], &seqs, &[]), vec!["7"]);
+    }
+
+    #[test]
+    fn seq_takes_one_value_per_scope() {
+        let mut parser = Parser {
+            vars: Vec::new(),
+            feeders: &[],
+            sequences: Sequences::new(),
+        };
+        let url = Template::parse("/items/{{seq}}", &mut parser).unwrap();
+        let body = Template::parse("{{seq}}-{{seq other}}", &mut parser).unwrap();
+        let seqs = parser.sequences;
+        assert_eq!(
+            render_all(&[&url, &body], &seqs, &[]),
+            vec!["/items/0", "0-0"]
+        );
+        assert_eq!(
+            render_all(&[&url, &body], &seqs, &[]),
+            vec!["/items/1", "1-1"]
+        );
+    }
+
+    #[test]
+    fn feeder_columns_resolve_from_one_row() {
+        let feeders = [feeder()];
+        let (t, seqs) = parse("{{host}}/{{users.id}}/{{users.name}}", &feeders).unwrap();
+        let states: Vec<FeederState> = feeders.iter().map(FeederState::new).collect();
+        assert_eq!(
+            render_all(&[&t], &seqs, &states),
+            vec!["example.com/1/alice"]
+        );
+        assert_eq!(render_all(&[&t], &seqs, &states), vec!["example.com/2/bob"]);
+        // A sequential feeder that has run dry renders nothing for its columns.
+        assert_eq!(render_all(&[&t], &seqs, &states), vec!["example.com//"]);
+    }
+}
@@ -1,18 +1,29 @@
 use crate::{
-    messages, static_assets,
+    checks::CompiledCheck,
+    extract::CompiledExtractor,
+    feeder, messages, metrics, static_assets,
     stats::{self, StatsCollector},
+    template,
 };
+use futures::{future, stream, StreamExt};
 use slog::{info, o, Logger};
 use std::{convert::Infallible, net::SocketAddr};
-use warp::{self, http::StatusCode, reply::Response, Filter, Rejection, Reply};
+use tokio_stream::wrappers::BroadcastStream;
+use warp::{self, http::StatusCode, reply::Response, sse, Filter, Rejection, Reply};
 
 use headers::{ContentType, HeaderMapExt};
 use tokio::sync::{watch, Mutex};
 
 use anyhow::Result as TaskResult;
 
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 #[derive(Clone)]
 struct State {
@@ -34,6 +45,7 @@ struct StatsResponse {
 struct SnapshotResponse {
     pub state: stats::WorkerState,
     pub elapsed: Option<u128>,
+    pub stage: Option<StageResponse>,
     pub tasks: u32,
     pub task_queue: u32,
     pub tasks_max: u32,
@@ -43,6 +55,14 @@ struct SnapshotResponse {
     pub stdev: f64,
     pub median: u64,
     pub p90: u64,
+    pub percentiles: Vec<messages::Percentile>,
+    pub corrected: LatencyResponse,
+    pub ttfb: LatencyResponse,
+    pub bytes_sent: u64,
+    pub rate_mb_sent: f64,
+    pub bytes_received: u64,
+    pub rate_mb_received: f64,
+    pub connections: ConnectionsResponse,
     pub count: u32,
     pub rate_count: f64,
     pub count_1xx: u32,
@@ -57,11 +77,195 @@ struct SnapshotResponse {
     pub rate_5xx: f64,
     pub count_fail: u32,
     pub rate_fail: f64,
+    pub count_timeout: u32,
+    pub rate_timeout: f64,
+    pub timeouts: LatencyResponse,
+    pub count_aborted: u32,
+    pub count_dropped: u32,
+    pub count_late: u32,
+    pub status_codes: BTreeMap<u16, StatusCodeResponse>,
+    pub errors: BTreeMap<messages::ErrorKind, u32>,
+    pub top_errors: Vec<messages::ErrorMessage>,
+    pub specs: Vec<SpecResponse>,
+    pub interval: WindowResponse,
+    pub window: WindowResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LatencyResponse {
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stdev: f64,
+    pub percentiles: Vec<messages::Percentile>,
+}
+
+impl From<&stats::LatencySnapshot> for LatencyResponse {
+    fn from(l: &stats::LatencySnapshot) -> LatencyResponse {
+        LatencyResponse {
+            min: l.min,
+            max: l.max,
+            mean: l.mean,
+            stdev: l.stdev,
+            percentiles: l.percentiles.clone(),
+        }
+    }
+}
+
+// Rates here are over the window itself, not the whole run.
+#[derive(Debug, Serialize, Deserialize)]
+struct WindowResponse {
+    pub length: u128,
+    pub count: u32,
+    pub rate_count: f64,
+    pub count_1xx: u32,
+    pub count_2xx: u32,
+    pub count_3xx: u32,
+    pub count_4xx: u32,
+    pub count_5xx: u32,
+    pub count_fail: u32,
+    pub rate_fail: f64,
+    pub count_timeout: u32,
+    pub rate_timeout: f64,
+    pub latency: LatencyResponse,
+}
+
+impl From<&stats::WindowSnapshot> for WindowResponse {
+    fn from(w: &stats::WindowSnapshot) -> WindowResponse {
+        let secs = w.length.as_secs_f64();
+        let rate = |count: u32| {
+            if secs > 0.0 {
+                f64::from(count) / secs
+            } else {
+                0.0
+            }
+        };
+        WindowResponse {
+            length: w.length.as_millis(),
+            count: w.count,
+            rate_count: rate(w.count),
+            count_1xx: w.count_1xx,
+            count_2xx: w.count_2xx,
+            count_3xx: w.count_3xx,
+            count_4xx: w.count_4xx,
+            count_5xx: w.count_5xx,
+            count_fail: w.count_fail,
+            rate_fail: rate(w.count_fail),
+            count_timeout: w.count_timeout,
+            rate_timeout: rate(w.count_timeout),
+            latency: LatencyResponse::from(&w.latency),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ConnectionsResponse {
+    pub new: u32,
+    pub reused: u32,
+    pub dns: LatencyResponse,
+    pub connect: LatencyResponse,
+    pub tls: LatencyResponse,
+}
+
+impl From<&stats::ConnectionsSnapshot> for ConnectionsResponse {
+    fn from(c: &stats::ConnectionsSnapshot) -> ConnectionsResponse {
+        ConnectionsResponse {
+            new: c.new,
+            reused: c.reused,
+            dns: LatencyResponse::from(&c.dns),
+            connect: LatencyResponse::from(&c.connect),
+            tls: LatencyResponse::from(&c.tls),
+        }
+    }
+}
+
+const BYTES_PER_MB: f64 = 1_000_000.0;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StatusCodeResponse {
+    pub count: u32,
+    pub rate: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SpecResponse {
+    pub index: usize,
+    pub name: Option<String>,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stdev: f64,
+    pub percentiles: Vec<messages::Percentile>,
+    pub count: u32,
+    pub rate_count: f64,
+    pub count_1xx: u32,
+    pub count_2xx: u32,
+    pub count_3xx: u32,
+    pub count_4xx: u32,
+    pub count_5xx: u32,
+    pub count_fail: u32,
+    pub count_timeout: u32,
+    pub count_aborted: u32,
+    pub checks: Vec<messages::CheckStatus>,
+    pub planned_share: f64,
+    pub share: f64,
+}
+
+impl SpecResponse {
+    // `total` is the count across every spec, for the share this one got.
+    fn new(s: &stats::SpecSnapshot, total: u32, elapsed: Option<Duration>) -> SpecResponse {
+        SpecResponse {
+            index: s.index,
+            name: s.name.clone(),
+            min: s.min,
+            max: s.max,
+            mean: s.mean,
+            stdev: s.stdev,
+            percentiles: s.percentiles.clone(),
+            count: s.count,
+            rate_count: elapsed
+                .map(|e| e.as_secs_f64())
+                .map(|e| f64::from(s.count) / e)
+                .unwrap_or(0.0),
+            count_1xx: s.count_1xx,
+            count_2xx: s.count_2xx,
+            count_3xx: s.count_3xx,
+            count_4xx: s.count_4xx,
+            count_5xx: s.count_5xx,
+            count_fail: s.count_fail,
+            count_timeout: s.count_timeout,
+            count_aborted: s.count_aborted,
+            checks: s.checks.clone(),
+            planned_share: s.planned_share,
+            share: if total > 0 {
+                f64::from(s.count) / f64::from(total)
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StageResponse {
+    pub index: usize,
+    pub target: f64,
+    pub elapsed: u128,
+}
+
+impl From<&messages::ActiveStage> for StageResponse {
+    fn from(s: &messages::ActiveStage) -> StageResponse {
+        StageResponse {
+            index: s.index,
+            target: s.target,
+            elapsed: s.elapsed.as_millis(),
+        }
+    }
 }
 
 impl From<&stats::Status> for StatsResponse {
     fn from(s: &stats::Status) -> StatsResponse {
-        let snapshot = s.snapshots.get(0);
+        let snapshot = s.snapshots.front();
         StatsResponse {
             id: s.id,
             hostname: s.hostname.clone(),
@@ -77,6 +281,7 @@ impl From<&stats::Snapshot> for SnapshotResponse {
         SnapshotResponse {
             state: s.state,
             elapsed: s.elapsed.map(|e| e.as_millis()),
+            stage: s.stage.as_ref().map(StageResponse::from),
             tasks: s.tasks,
             task_queue: s.task_queue,
             tasks_max: s.tasks_max,
@@ -86,6 +291,22 @@ impl From<&stats::Snapshot> for SnapshotResponse {
             stdev: s.stdev,
             median: s.median,
             p90: s.p90,
+            percentiles: s.percentiles.clone(),
+            corrected: LatencyResponse::from(&s.corrected),
+            ttfb: LatencyResponse::from(&s.ttfb),
+            bytes_sent: s.bytes_sent,
+            rate_mb_sent: s
+                .elapsed
+                .map(|e| e.as_secs_f64())
+                .map(|e| s.bytes_sent as f64 / BYTES_PER_MB / e)
+                .unwrap_or(0.0),
+            bytes_received: s.bytes_received,
+            rate_mb_received: s
+                .elapsed
+                .map(|e| e.as_secs_f64())
+                .map(|e| s.bytes_received as f64 / BYTES_PER_MB / e)
+                .unwrap_or(0.0),
+            connections: ConnectionsResponse::from(&s.connections),
             count: s.count,
             rate_count: s
                 .elapsed
@@ -128,17 +349,121 @@ impl From<&stats::Snapshot> for SnapshotResponse {
                 .map(|e| e.as_secs_f64())
                 .map(|e| f64::from(s.count_fail) / e)
                 .unwrap_or(0.0),
+            count_timeout: s.count_timeout,
+            rate_timeout: s
+                .elapsed
+                .map(|e| e.as_secs_f64())
+                .map(|e| f64::from(s.count_timeout) / e)
+                .unwrap_or(0.0),
+            timeouts: LatencyResponse::from(&s.timeouts),
+            count_aborted: s.count_aborted,
+            count_dropped: s.count_dropped,
+            count_late: s.count_late,
+            status_codes: s
+                .status_codes
+                .iter()
+                .map(|(code, count)| {
+                    (
+                        *code,
+                        StatusCodeResponse {
+                            count: *count,
+                            rate: s
+                                .elapsed
+                                .map(|e| e.as_secs_f64())
+                                .map(|e| f64::from(*count) / e)
+                                .unwrap_or(0.0),
+                        },
+                    )
+                })
+                .collect(),
+            errors: s.errors.clone(),
+            top_errors: s.error_messages.clone(),
+            specs: s
+                .specs
+                .iter()
+                .map(|spec| {
+                    SpecResponse::new(spec, s.specs.iter().map(|s| s.count).sum(), s.elapsed)
+                })
+                .collect(),
+            interval: WindowResponse::from(&s.interval),
+            window: WindowResponse::from(&s.window),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RunResponse {
+    id: String,
+    state: stats::RunState,
+    started: u128,
+    finished: Option<u128>,
+}
+
+fn epoch_millis(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+impl From<&stats::Run> for RunResponse {
+    fn from(r: &stats::Run) -> RunResponse {
+        RunResponse {
+            id: r.id.clone(),
+            state: r.state,
+            started: epoch_millis(r.started),
+            finished: r.finished.map(epoch_millis),
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct AllStatsResponse {
+    run: Option<RunResponse>,
+    cluster: Option<SnapshotResponse>,
     items: Vec<StatsResponse>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryQuery {
+    resolution_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryPointResponse {
+    elapsed: u128,
+    timestamp: u128,
+    #[serde(flatten)]
+    window: WindowResponse,
+}
+
+impl From<&stats::HistoryBucket> for HistoryPointResponse {
+    fn from(b: &stats::HistoryBucket) -> HistoryPointResponse {
+        HistoryPointResponse {
+            elapsed: b.elapsed.as_millis(),
+            timestamp: epoch_millis(b.timestamp),
+            window: WindowResponse::from(&b.window),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WorkerHistoryResponse {
+    id: u32,
+    hostname: Option<String>,
+    points: Vec<HistoryPointResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryResponse {
+    run: Option<RunResponse>,
+    resolution: u128,
+    cluster: Vec<HistoryPointResponse>,
+    items: Vec<WorkerHistoryResponse>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct NewRequestSpec {
+    name: Option<String>,
     version: Option<messages::HttpVersion>,
     method: messages::RequestMethod,
     url: String,
@@ -146,39 +471,247 @@ struct NewRequestSpec {
     headers: Option<HashMap<String, String>>,
     random_querystring: Option<String>,
     random_header: Option<String>,
+    connect_timeout_ms: Option<u64>,
+    request_timeout_ms: Option<u64>,
+    checks: Option<Vec<messages::Check>>,
+    extract: Option<Vec<messages::Extractor>>,
+    weight: Option<f64>,
 }
 
 impl From<NewRequestSpec> for messages::RequestSpec {
     fn from(r: NewRequestSpec) -> Self {
         messages::RequestSpec {
+            name: r.name,
             version: r.version.unwrap_or(messages::HttpVersion::Http11),
             method: r.method,
             url: r.url,
             body: r.body,
-            headers: r.headers.unwrap_or_else(HashMap::new),
+            headers: r.headers.unwrap_or_default(),
             random_querystring: r.random_querystring,
             random_header: r.random_header,
+            connect_timeout: r.connect_timeout_ms.map(Duration::from_millis),
+            request_timeout: r.request_timeout_ms.map(Duration::from_millis),
+            checks: r.checks.unwrap_or_default(),
+            extract: r.extract.unwrap_or_default(),
+            weight: r.weight.unwrap_or(1.0),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NewStage {
+    duration_secs: u64,
+    target: f64,
+    interpolation: Option<messages::Interpolation>,
+}
+
+impl From<NewStage> for messages::Stage {
+    fn from(s: NewStage) -> Self {
+        messages::Stage {
+            duration: Duration::from_secs(s.duration_secs),
+            target: s.target,
+            interpolation: s.interpolation.unwrap_or(messages::Interpolation::Linear),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum FeederFormat {
+    Csv,
+    Jsonl,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NewFeeder {
+    name: String,
+    format: FeederFormat,
+    data: String,
+    order: Option<messages::FeedOrder>,
+    partition: Option<bool>,
+}
+
+impl TryFrom<NewFeeder> for messages::Feeder {
+    type Error = anyhow::Error;
+
+    fn try_from(f: NewFeeder) -> Result<Self, Self::Error> {
+        let (columns, rows) = match f.format {
+            FeederFormat::Csv => feeder::parse_csv(&f.data),
+            FeederFormat::Jsonl => feeder::parse_jsonl(&f.data),
+        }
+        .map_err(|e| anyhow!("feeder {}: {}", f.name, e))?;
+        Ok(messages::Feeder {
+            name: f.name,
+            columns,
+            rows,
+            order: f.order.unwrap_or(messages::FeedOrder::Circular),
+            partition: f.partition.unwrap_or(false),
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct StartCommandRequest {
     requests: Vec<NewRequestSpec>,
     strategy: Option<messages::AttackStrategy>,
     max_concurrency: Option<u32>,
+    rate: Option<f64>,
+    stages: Option<Vec<NewStage>>,
+    duration_secs: Option<u64>,
+    max_requests: Option<u64>,
+    percentiles: Option<Vec<f64>>,
+    variables: Option<HashMap<String, String>>,
+    feeders: Option<Vec<NewFeeder>>,
+    abort_on_failure: Option<bool>,
+    connect_timeout_ms: Option<u64>,
+    request_timeout_ms: Option<u64>,
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout_secs: Option<u64>,
+    new_connection_per_request: Option<bool>,
+    virtual_users: Option<bool>,
+    session_reset_iterations: Option<u64>,
+    step_think_time: Option<messages::ThinkTime>,
+    iteration_think_time: Option<messages::ThinkTime>,
+    iteration_interval_ms: Option<u64>,
+    expected_interval_ms: Option<u64>,
+    window_secs: Option<u64>,
+    history_retention_secs: Option<u64>,
 }
 
-impl Into<messages::Command> for StartCommandRequest {
-    fn into(self) -> messages::Command {
-        messages::Command::start(
-            self.requests
+const DEFAULT_PERCENTILES: &[f64] = &[50.0, 90.0, 95.0, 99.0, 99.9, 99.99];
+const DEFAULT_WINDOW_SECS: u64 = 60;
+// One request every ~17 minutes; anything slower can't be scheduled sensibly.
+const MIN_RATE: f64 = 0.001;
+// Workers keep a histogram per heartbeat across the window, ~140KB each.
+const MAX_WINDOW_SECS: u64 = 600;
+// Matches the worker heartbeat, so by default nothing is merged.
+const DEFAULT_RESOLUTION_SECS: u64 = 5;
+
+// Fails if a feeder can't be parsed or a template doesn't compile, so bad runs
+// are rejected before any worker sees them.
+impl TryFrom<StartCommandRequest> for messages::Command {
+    type Error = anyhow::Error;
+
+    fn try_from(r: StartCommandRequest) -> Result<Self, Self::Error> {
+        let spec = messages::RunSpec {
+            run_id: uuid::Uuid::new_v4().to_string(),
+            requests: r
+                .requests
                 .into_iter()
                 .map(messages::RequestSpec::from)
                 .collect(),
-            self.strategy.unwrap_or(messages::AttackStrategy::Random),
-            self.max_concurrency.unwrap_or(50),
-        )
+            strategy: r.strategy.unwrap_or(messages::AttackStrategy::Random),
+            max_concurrency: r.max_concurrency.unwrap_or(50),
+            rate: r.rate,
+            stages: r
+                .stages
+                .unwrap_or_default()
+                .into_iter()
+                .map(messages::Stage::from)
+                .collect(),
+            duration: r.duration_secs.map(Duration::from_secs),
+            max_requests: r.max_requests,
+            percentiles: r
+                .percentiles
+                .unwrap_or_else(|| DEFAULT_PERCENTILES.to_vec()),
+            variables: r.variables.unwrap_or_default(),
+            feeders: r
+                .feeders
+                .unwrap_or_default()
+                .into_iter()
+                .map(messages::Feeder::try_from)
+                .collect::<Result<_, _>>()?,
+            abort_on_failure: r.abort_on_failure.unwrap_or(false),
+            connect_timeout: r.connect_timeout_ms.map(Duration::from_millis),
+            request_timeout: r.request_timeout_ms.map(Duration::from_millis),
+            pool: messages::Pool {
+                max_idle_per_host: r.pool_max_idle_per_host,
+                idle_timeout: r.pool_idle_timeout_secs.map(Duration::from_secs),
+                new_connection_per_request: r.new_connection_per_request.unwrap_or(false),
+            },
+            sessions: messages::Sessions {
+                virtual_users: r.virtual_users.unwrap_or(false),
+                reset_every: r.session_reset_iterations.filter(|n| *n > 0),
+            },
+            pacing: messages::Pacing {
+                step_think: r.step_think_time,
+                iteration_think: r.iteration_think_time,
+                interval: r.iteration_interval_ms.map(Duration::from_millis),
+            },
+            expected_interval: r
+                .expected_interval_ms
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            window: Duration::from_secs(r.window_secs.unwrap_or(DEFAULT_WINDOW_SECS)),
+            shard: messages::Shard::single(),
+        };
+        template::parse_run(&spec)?;
+        if let Some(rate) = spec.rate {
+            if !rate.is_finite() || rate < MIN_RATE {
+                return Err(anyhow!("rate must be at least {}", MIN_RATE));
+            }
+        }
+        for (i, s) in spec.stages.iter().enumerate() {
+            if !s.target.is_finite() || s.target < 0.0 {
+                return Err(anyhow!("stage {}: target must be zero or more", i));
+            }
+            if spec.rate.is_some() && s.target > 0.0 && s.target < MIN_RATE {
+                return Err(anyhow!(
+                    "stage {}: target must be 0 or at least {}",
+                    i,
+                    MIN_RATE
+                ));
+            }
+        }
+        if spec.window > Duration::from_secs(MAX_WINDOW_SECS) {
+            return Err(anyhow!("window_secs can be at most {}", MAX_WINDOW_SECS));
+        }
+        for (i, r) in spec.requests.iter().enumerate() {
+            if !r.weight.is_finite() || r.weight < 0.0 {
+                return Err(anyhow!("request {}: weight must be zero or more", i));
+            }
+        }
+        for think in [spec.pacing.step_think, spec.pacing.iteration_think]
+            .iter()
+            .flatten()
+        {
+            if let messages::ThinkTime::Uniform { min_ms, max_ms } = think {
+                if min_ms > max_ms {
+                    return Err(anyhow!("think time minimum is above its maximum"));
+                }
+            }
+        }
+        // With a rate, iterations start on its schedule; waiting between them
+        // would hold a slot and delay the requests due next.
+        if spec.rate.is_some()
+            && (spec.pacing.iteration_think.is_some() || spec.pacing.interval.is_some())
+        {
+            return Err(anyhow!(
+                "iteration_think_time and iteration_interval_ms can't be combined with rate"
+            ));
+        }
+        // A rate or pacing schedule already charges requests for starting late;
+        // an expected interval on top would correct the same wait twice.
+        if spec.expected_interval.is_some()
+            && (spec.rate.is_some() || spec.pacing.interval.is_some())
+        {
+            return Err(anyhow!(
+                "expected_interval_ms can't be combined with rate or iteration_interval_ms"
+            ));
+        }
+        if let messages::AttackStrategy::Weighted = spec.strategy {
+            if !spec.requests.iter().any(|r| r.weight > 0.0) {
+                return Err(anyhow!(
+                    "Weighted needs at least one request with a positive weight"
+                ));
+            }
+        }
+        for c in spec.requests.iter().flat_map(|r| r.checks.iter()) {
+            CompiledCheck::new(c).map_err(|err| anyhow!("check {}: {}", c, err))?;
+        }
+        for e in spec.requests.iter().flat_map(|r| r.extract.iter()) {
+            CompiledExtractor::new(e).map_err(|err| anyhow!("extractor {}: {}", e.name, err))?;
+        }
+        Ok(messages::Command::start(spec))
     }
 }
 
@@ -187,16 +720,98 @@ struct CommandResponse {
     command: messages::Command,
 }
 
-async fn get_stats(state: State) -> Result<impl Reply, Infallible> {
+#[derive(Debug, Serialize, Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+fn all_stats(state: &State) -> AllStatsResponse {
     let stats = state
         .stats
-        .with_stats(|map| map.iter().map(|(_, s)| StatsResponse::from(s)).collect());
-    let r = AllStatsResponse { items: stats };
+        .with_stats(|map| map.values().map(StatsResponse::from).collect());
+    AllStatsResponse {
+        run: state.stats.run().as_ref().map(RunResponse::from),
+        cluster: state.stats.aggregate().as_ref().map(SnapshotResponse::from),
+        items: stats,
+    }
+}
+
+async fn get_stats(state: State) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&all_stats(&state)))
+}
+
+fn sse_event<T: Serialize>(name: &str, body: &T) -> Option<sse::Event> {
+    sse::Event::default().event(name).json_data(body).ok()
+}
+
+fn worker_event(state: &State, name: &str, id: u32) -> Option<sse::Event> {
+    state
+        .stats
+        .with_stats(|map| map.get(&id).map(StatsResponse::from))
+        .and_then(|w| sse_event(name, &w))
+}
+
+// Sends the body of GET /stats every time a worker reports in, and the
+// worker's own entry when one connects or disconnects. Starts off with the
+// current stats so clients don't have to wait for the next heartbeat.
+async fn stream_stats(state: State) -> Result<impl Reply, Infallible> {
+    let initial = sse_event("stats", &all_stats(&state));
+    let updates = BroadcastStream::new(state.stats.subscribe()).filter_map(move |e| {
+        let event = match e {
+            Ok(stats::StatsEvent::Updated) => sse_event("stats", &all_stats(&state)),
+            Ok(stats::StatsEvent::Connected(id)) => worker_event(&state, "connected", id),
+            Ok(stats::StatsEvent::Disconnected(id)) => worker_event(&state, "disconnected", id),
+            // Missed events from falling behind; the next update catches up.
+            Err(_) => None,
+        };
+        future::ready(event.map(Ok::<_, Infallible>))
+    });
+    let events = stream::iter(initial.map(Ok)).chain(updates);
+    Ok(sse::reply(sse::keep_alive().stream(events)))
+}
+
+async fn get_history(state: State, query: HistoryQuery) -> Result<impl Reply, Infallible> {
+    let resolution = Duration::from_secs(
+        query
+            .resolution_secs
+            .filter(|s| *s > 0)
+            .unwrap_or(DEFAULT_RESOLUTION_SECS),
+    );
+    let history = state.stats.history(resolution);
+    let points = |buckets: &[stats::HistoryBucket]| {
+        buckets
+            .iter()
+            .map(HistoryPointResponse::from)
+            .collect::<Vec<_>>()
+    };
+    let r = HistoryResponse {
+        run: state.stats.run().as_ref().map(RunResponse::from),
+        resolution: resolution.as_millis(),
+        cluster: points(&history.cluster),
+        items: history
+            .workers
+            .iter()
+            .map(|(id, hostname, buckets)| WorkerHistoryResponse {
+                id: *id,
+                hostname: hostname.clone(),
+                points: points(buckets),
+            })
+            .collect(),
+    };
     Ok(warp::reply::json(&r))
 }
 
+async fn get_metrics(state: State) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::with_header(
+        metrics::render(&state.stats),
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
 async fn stop_workers(state: State) -> Result<impl Reply, Infallible> {
     info!(state.logger, "Stopping workers");
+    state.stats.stop_run();
     let _ = state
         .command_tx
         .clone()
@@ -209,6 +824,7 @@ async fn stop_workers(state: State) -> Result<impl Reply, Infallible> {
 async fn reset_workers(state: State) -> Result<impl Reply, Infallible> {
     info!(state.logger, "Resetting workers");
     state.stats.prune_disconnected();
+    state.stats.clear_run();
     let _ = state
         .command_tx
         .clone()
@@ -220,12 +836,33 @@ async fn reset_workers(state: State) -> Result<impl Reply, Infallible> {
 
 async fn start_workers(state: State, cmd: StartCommandRequest) -> Result<impl Reply, Infallible> {
     info!(state.logger, "Sending command => {:?}", &cmd);
-    let c: messages::Command = cmd.into();
+    let history_retention = cmd
+        .history_retention_secs
+        .map(Duration::from_secs)
+        .unwrap_or(stats::DEFAULT_HISTORY_RETENTION);
+    let c = match messages::Command::try_from(cmd) {
+        Ok(c) => c,
+        Err(e) => {
+            let body = ErrorResponse {
+                error: e.to_string(),
+            };
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&body),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+    if let messages::Command::Start(ref spec) = c {
+        state.stats.start_run(&spec.run_id, history_retention);
+    }
     let resp_body = CommandResponse { command: c.clone() };
 
     let _ = state.command_tx.clone().lock().await.send(c);
 
-    Ok(warp::reply::json(&resp_body))
+    Ok(warp::reply::with_status(
+        warp::reply::json(&resp_body),
+        StatusCode::OK,
+    ))
 }
 
 async fn clear_disconnected(state: State) -> Result<impl Reply, Infallible> {
@@ -266,6 +903,22 @@ pub async fn webserver_task(
         command_tx: Arc::new(Mutex::new(command_tx)),
     };
 
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(get_metrics);
+
+    let stream = warp::path!("stats" / "stream")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(stream_stats);
+
+    let history = warp::path!("stats" / "history")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and(warp::query::<HistoryQuery>())
+        .and_then(get_history);
+
     let stats = warp::path("stats")
         .and(warp::get())
         .and(with_state(state.clone()))
@@ -297,7 +950,15 @@ pub async fn webserver_task(
         .and_then(static_file);
 
     let workers = warp::path("workers").and(start.or(stop).or(reset).or(clear_disconnected));
-    let routes = warp::any().and(index_page.or(stats).or(workers).or(static_file));
+    let routes = warp::any().and(
+        index_page
+            .or(metrics)
+            .or(stream)
+            .or(history)
+            .or(stats)
+            .or(workers)
+            .or(static_file),
+    );
     info!(logger, "Starting webserver at {}", addr);
     let addr: SocketAddr = addr.parse().unwrap();
     warp::serve(routes).run(addr).await;
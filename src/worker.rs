@@ -1,13 +1,25 @@
 use anyhow::Result;
 use async_tungstenite::tokio::connect_async;
-use futures::{pin_mut, select, sink::SinkExt, stream, StreamExt, TryStreamExt};
-use hyper::{self, Client};
-use hyper_rustls::HttpsConnector;
-use rand::{self, seq::SliceRandom};
+use futures::{
+    pin_mut, select,
+    sink::SinkExt,
+    stream::{self, FusedStream, Stream},
+    FutureExt, StreamExt, TryStreamExt,
+};
+use hyper::{self, body::HttpBody, Client};
+use rand::{
+    self,
+    distributions::{Distribution, WeightedIndex},
+    Rng,
+};
 use slog::{debug, error, info, o, warn, Logger};
 use std::{
-    convert::TryInto,
-    sync::Arc,
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 use tokio_stream::wrappers::{ReceiverStream, WatchStream};
@@ -21,12 +33,26 @@ use tokio::{
     time,
 };
 
+use crate::checks::{self, CompiledCheck};
+use crate::connector::Connector;
+use crate::cookies::CookieJar;
+use crate::errors;
+use crate::extract::CompiledExtractor;
+use crate::feeder::FeederState;
 use crate::messages;
-use crate::stats::Stats;
+use crate::pacing;
+use crate::profile::{LoadProfile, Target};
+use crate::stats::{as_micros, Stats};
+use crate::template::{self, RequestTemplate, Scope, Sequences};
+use crate::worker_server::{self, Health};
 
-type ClientConnector = HttpsConnector<hyper::client::HttpConnector>;
+type ClientConnector = Connector;
 
-#[derive(Debug)]
+const CONTROL_INTERVAL: Duration = Duration::from_millis(100);
+const LATE_THRESHOLD: Duration = Duration::from_millis(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
 struct State {
     commands: mpsc::Sender<messages::Command>,
     stats: watch::Receiver<messages::Status>,
@@ -71,10 +97,16 @@ async fn handle_message(
 enum Action {
     Incoming(Result<Message>),
     Outgoing(Message),
-    Stats(messages::Status),
+    Stats(Box<messages::Status>),
 }
 
-async fn run(logger: Logger, addr: String, state: State) -> Result<()> {
+pub fn hostname() -> Option<String> {
+    hostname::get()
+        .map(|h| h.into_string().unwrap_or_else(|_| String::new()))
+        .ok()
+}
+
+async fn run(logger: Logger, addr: String, state: State, health: Health) -> Result<()> {
     info!(logger, "Connecting to {}", addr);
     let url = url::Url::parse(&addr)?;
     debug!(logger, "parsed URL {}", url);
@@ -87,20 +119,19 @@ async fn run(logger: Logger, addr: String, state: State) -> Result<()> {
     }
     let (ws_stream, _response) = res?;
     debug!(logger, "Successfully connected");
+    health.connected();
     let (tx, rx) = mpsc::channel(100);
     let (mut outgoing, incoming) = ws_stream.split();
     let handle_incoming = incoming.map_err(|e| e.into()).map(Action::Incoming);
     let handle_outgoing = ReceiverStream::new(rx).map(Action::Outgoing);
-    let handle_stats = WatchStream::new(state.stats.clone()).map(Action::Stats);
+    let handle_stats = WatchStream::new(state.stats.clone()).map(|s| Action::Stats(Box::new(s)));
     let s1 = stream::select(handle_stats, handle_incoming);
     let mut combined = stream::select(s1, handle_outgoing);
     loop {
         if let Some(r) = combined.next().await {
             match r {
                 Action::Stats(mut s) => {
-                    s.hostname = hostname::get()
-                        .map(|h| h.into_string().unwrap_or_else(|_| String::new()))
-                        .ok();
+                    s.hostname = hostname();
                     let s = s.as_message()?;
                     let _ = tx.send(s).await;
                 }
@@ -132,37 +163,72 @@ async fn run(logger: Logger, addr: String, state: State) -> Result<()> {
     Ok(())
 }
 
+#[derive(Debug)]
+enum ExecutorEvent {
+    Command(Option<messages::Command>),
+    Finished(std::result::Result<Result<()>, tokio::task::JoinError>),
+}
+
 async fn command_executor(
     logger: Logger,
     stats: Stats,
+    health: Health,
     mut rx: mpsc::Receiver<messages::Command>,
 ) -> Result<()> {
     debug!(logger, "Started executor task");
     let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
     let shutdown_tx = Arc::new(Mutex::new(Some(shutdown_tx)));
     let shutdown_rx = Arc::new(Mutex::new(shutdown_rx));
-    let mut handle = None;
-    while let Some(cmd) = rx.recv().await {
+    let mut handle: Option<tokio::task::JoinHandle<Result<()>>> = None;
+    // The coordinator replays its latest command to a worker that reconnects.
+    let mut last_run: Option<String> = None;
+    loop {
+        let event = match handle.as_mut() {
+            Some(h) => select! {
+                cmd = rx.recv().fuse() => ExecutorEvent::Command(cmd),
+                res = h.fuse() => ExecutorEvent::Finished(res),
+            },
+            None => ExecutorEvent::Command(rx.recv().await),
+        };
+        let cmd = match event {
+            ExecutorEvent::Command(Some(cmd)) => cmd,
+            ExecutorEvent::Command(None) => break,
+            ExecutorEvent::Finished(res) => {
+                info!(logger, "Run finished");
+                handle = None;
+                stats.stop();
+                if let Err(e) = res? {
+                    error!(logger, "Scheduler failed: {}", e);
+                }
+                continue;
+            }
+        };
         let shutdown_tx = shutdown_tx.clone();
         info!(logger, "Received command {:?}", cmd);
+        health.command(&cmd);
         match cmd {
-            messages::Command::Start {
-                requests,
-                strategy,
-                max_concurrency,
-            } => {
-                stats.start();
+            messages::Command::Start(spec) => {
+                if last_run.as_deref() == Some(spec.run_id.as_str()) {
+                    info!(logger, "Ignoring repeated start of run {}", spec.run_id);
+                    continue;
+                }
+                last_run = Some(spec.run_id.clone());
+                if let Some(h) = handle.take() {
+                    debug!(logger, "Stopping previous run");
+                    shutdown_tx.lock().await.take().map(|l| l.send(()));
+                    let _ = h.await?;
+                }
+                stats.start(&spec);
                 {
                     let (tx, rx) = oneshot::channel::<()>();
                     *shutdown_tx.clone().lock().await = Some(tx);
                     *shutdown_rx.clone().lock().await = rx;
                 }
+                let run = Run::new(&logger, *spec, &stats);
                 let h = tokio::spawn(task_scheduler(
                     logger.new(o!("task" => "scheduler")),
-                    requests,
+                    run,
                     stats.clone(),
-                    strategy,
-                    max_concurrency,
                     shutdown_rx.clone(),
                 ));
                 handle = Some(h);
@@ -189,57 +255,466 @@ async fn command_executor(
     Ok(())
 }
 
-async fn task_scheduler(
-    logger: Logger,
+struct Run {
     requests: Vec<messages::RequestSpec>,
-    mut stats: Stats,
     strategy: messages::AttackStrategy,
+    // Only for Weighted runs.
+    mix: Option<WeightedIndex<f64>>,
+    // The next spec for RoundRobin runs.
+    next_spec: AtomicUsize,
+    // One per request spec; specs that can share a connection pool share a
+    // client.
+    clients: Vec<Client<ClientConnector, hyper::Body>>,
+    checks: Vec<Vec<CompiledCheck>>,
+    extractors: Vec<Vec<CompiledExtractor>>,
+    abort_on_failure: bool,
+    templates: Vec<RequestTemplate>,
+    sequences: Sequences,
+    variables: HashMap<String, String>,
+    feeders: Vec<FeederState>,
+    // Set once a sequential feeder runs out of rows.
+    exhausted: AtomicBool,
+    sessions: messages::Sessions,
+    pacing: messages::Pacing,
+    worker_id: u32,
     max_concurrency: u32,
+    open_model: bool,
+    profile: LoadProfile,
+    duration: Option<Duration>,
+    requests_left: Option<AtomicU64>,
+}
+
+impl Run {
+    fn new(logger: &Logger, spec: messages::RunSpec, stats: &Stats) -> Run {
+        let max_concurrency = u32::max(spec.max_concurrency, 1);
+        let shard = spec.shard;
+        // A rate and its stage targets are for the whole run; concurrency is
+        // per worker.
+        let base = match spec.rate {
+            Some(rate) => shard.share(rate),
+            None => f64::from(max_concurrency),
+        };
+        let mut stages = spec.stages.clone();
+        if spec.rate.is_some() {
+            for s in stages.iter_mut() {
+                s.target = shard.share(s.target);
+            }
+        }
+        // The coordinator rejects runs with bad templates, so falling back to
+        // literal text should only happen with a mismatched coordinator.
+        let (templates, sequences) = template::parse_run(&spec).unwrap_or_else(|e| {
+            warn!(logger, "Invalid template, sending requests verbatim: {}", e);
+            (
+                spec.requests.iter().map(RequestTemplate::literal).collect(),
+                Sequences::new(),
+            )
+        });
+        let connector = Connector::new(logger, stats.clone());
+        // Resolve per-spec overrides against the run defaults up front.
+        let (connect_timeout, request_timeout) = (spec.connect_timeout, spec.request_timeout);
+        let requests: Vec<_> = spec
+            .requests
+            .into_iter()
+            .map(|mut r| {
+                r.connect_timeout = r.connect_timeout.or(connect_timeout);
+                r.request_timeout = r.request_timeout.or(request_timeout);
+                r
+            })
+            .collect();
+        let mut builder = Client::builder();
+        if spec.pool.new_connection_per_request {
+            // With no idle connections allowed hyper never pools, so every
+            // request gets a fresh connection.
+            builder.pool_max_idle_per_host(0);
+        } else if let Some(max_idle) = spec.pool.max_idle_per_host {
+            builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout) = spec.pool.idle_timeout {
+            builder.pool_idle_timeout(idle_timeout);
+        }
+        let mut shared = HashMap::new();
+        let clients = requests
+            .iter()
+            .map(|r| {
+                shared
+                    .entry((r.version, r.connect_timeout))
+                    .or_insert_with(|| {
+                        let connector = connector.clone().with_connect_timeout(r.connect_timeout);
+                        builder
+                            .clone()
+                            .http2_only(r.version == messages::HttpVersion::Http2)
+                            .build(connector)
+                    })
+                    .clone()
+            })
+            .collect();
+        let checks = requests
+            .iter()
+            .map(|r| {
+                r.checks
+                    .iter()
+                    .map(|c| {
+                        CompiledCheck::new(c).unwrap_or_else(|e| {
+                            warn!(logger, "Invalid check {}: {}", c, e);
+                            CompiledCheck::Invalid
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+        let extractors = requests
+            .iter()
+            .map(|r| {
+                r.extract
+                    .iter()
+                    .filter_map(|e| match CompiledExtractor::new(e) {
+                        Ok(e) => Some(e),
+                        Err(err) => {
+                            warn!(logger, "Invalid extractor {}: {}", e.name, err);
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let mix = match spec.strategy {
+            messages::AttackStrategy::Weighted => {
+                WeightedIndex::new(requests.iter().map(|r| r.weight))
+                    .map_err(|e| warn!(logger, "Invalid weights, picking uniformly: {}", e))
+                    .ok()
+            }
+            _ => None,
+        };
+        Run {
+            requests,
+            strategy: spec.strategy,
+            mix,
+            next_spec: AtomicUsize::new(0),
+            clients,
+            checks,
+            extractors,
+            abort_on_failure: spec.abort_on_failure,
+            templates,
+            sequences,
+            variables: spec.variables,
+            feeders: spec.feeders.iter().map(FeederState::new).collect(),
+            exhausted: AtomicBool::new(false),
+            sessions: spec.sessions,
+            pacing: spec.pacing,
+            worker_id: shard.index,
+            max_concurrency,
+            open_model: spec.rate.is_some(),
+            profile: LoadProfile::new(base, stages),
+            duration: spec.duration,
+            requests_left: spec.max_requests.map(|m| AtomicU64::new(shard.split(m))),
+        }
+    }
+
+    // None once the run has reached any of its limits.
+    fn target(&self, elapsed: Duration) -> Option<Target> {
+        if self.duration.map(|d| elapsed >= d).unwrap_or(false) {
+            return None;
+        }
+        if let Some(ref left) = self.requests_left {
+            if left.load(Ordering::Acquire) == 0 {
+                return None;
+            }
+        }
+        if self.exhausted.load(Ordering::Acquire) {
+            return None;
+        }
+        self.profile.at(elapsed)
+    }
+
+    fn scope(&self) -> Scope<'_> {
+        Scope::new(
+            self.worker_id,
+            &self.sequences,
+            &self.variables,
+            &self.feeders,
+        )
+    }
+
+    // The spec to send for strategies that make one request per iteration.
+    fn pick(&self) -> usize {
+        let mut rng = rand::thread_rng();
+        match (self.strategy, &self.mix) {
+            (messages::AttackStrategy::Weighted, Some(mix)) => mix.sample(&mut rng),
+            (messages::AttackStrategy::RoundRobin, _) => {
+                self.next_spec.fetch_add(1, Ordering::AcqRel) % self.requests.len()
+            }
+            _ => rng.gen_range(0..self.requests.len()),
+        }
+    }
+
+    // When a virtual user that started an iteration at `started` may begin its
+    // next one, if it has to wait at all.
+    fn next_iteration(&self, started: time::Instant) -> Option<time::Instant> {
+        let think = self.pacing.iteration_think.as_ref().map(pacing::sample);
+        let after_think = think.map(|t| time::Instant::now() + t);
+        let paced = self.pacing.interval.map(|i| started + i);
+        after_think.max(paced)
+    }
+
+    async fn step_think(&self) {
+        if let Some(ref think) = self.pacing.step_think {
+            time::sleep(pacing::sample(think)).await;
+        }
+    }
+
+    fn take_request(&self) -> bool {
+        if self.exhausted.load(Ordering::Acquire) {
+            return false;
+        }
+        match self.requests_left {
+            Some(ref left) => left
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+                .is_ok(),
+            None => true,
+        }
+    }
+}
+
+// Lets in-flight tasks complete once a run has reached its end on its own, so
+// request limits are honoured exactly. A stop request still cuts it short.
+async fn drain<S>(logger: &Logger, future_list: &mut S, shutdown: &Mutex<oneshot::Receiver<()>>)
+where
+    S: Stream + FusedStream + Unpin,
+{
+    debug!(logger, "Draining in-flight tasks");
+    let mut shutdown = shutdown.lock().await;
+    // An empty list that has already been polled is terminated, and select!
+    // would skip it and wait for a stop that may never come.
+    while !future_list.is_terminated() {
+        select! {
+            res = future_list.next() => {
+                if res.is_none() {
+                    break;
+                }
+            },
+            _ = (&mut *shutdown).fuse() => {
+                info!(logger, "Received stop message");
+                break;
+            }
+        }
+    }
+}
+
+async fn task_scheduler(
+    logger: Logger,
+    run: Run,
+    stats: Stats,
     shutdown: Arc<Mutex<oneshot::Receiver<()>>>,
 ) -> Result<()> {
-    if requests.is_empty() {
+    if run.requests.is_empty() {
         return Ok(());
     }
     debug!(logger, "Task Scheduler starting");
+    if run.open_model {
+        info!(logger, "Max in flight: {}", run.max_concurrency);
+        rate_loop(logger.clone(), &run, stats, shutdown).await
+    } else {
+        info!(logger, "Max Batches: {}", run.profile.max());
+        concurrency_loop(logger.clone(), &run, stats, shutdown).await
+    }
+    info!(logger, "Task Scheduler shutting down");
+    Ok(())
+}
+
+// The semaphore holds one permit per task allowed in flight. Permits are
+// returned as tasks are reaped; when the target drops, permits are reclaimed
+// from the pool where possible and otherwise withheld as tasks finish.
+async fn concurrency_loop(
+    logger: Logger,
+    run: &Run,
+    stats: Stats,
+    shutdown: Arc<Mutex<oneshot::Receiver<()>>>,
+) {
+    let semaphore = Semaphore::new(false, 0);
+    let mut limit: u32 = 0;
+    let mut owed: u32 = 0;
     let mut id: u64 = 0;
-    let max_batches: u32 = u32::max(max_concurrency, 1);
-    let semaphore = Arc::new(Semaphore::new(false, max_batches as usize));
-    stats.record_task_max(max_batches);
-    info!(logger, "Max Batches: {}", max_batches);
-    let https = HttpsConnector::with_native_roots();
+    // Virtual users not currently running an iteration, and since when.
+    let mut idle: Vec<(VirtualUser, Instant)> = Vec::new();
     let mut future_list = stream::FuturesUnordered::new();
+    let mut control = time::interval(CONTROL_INTERVAL);
+    let started = time::Instant::now();
     loop {
         if shutdown.lock().await.try_recv().is_ok() {
             info!(logger, "Received stop message");
             break;
         }
+        let target = match run.target(started.elapsed()) {
+            Some(t) => t,
+            None => {
+                info!(logger, "Run complete");
+                drain(&logger, &mut future_list, &shutdown).await;
+                break;
+            }
+        };
         let mut stats = stats.clone();
+        stats.record_stage(target.stage);
+        let wanted = target.value.round().max(0.0) as u32;
+        if wanted > limit {
+            let repaid = u32::min(wanted - limit, owed);
+            owed -= repaid;
+            semaphore.release((wanted - limit - repaid) as usize);
+        } else if wanted < limit {
+            let shrink = limit - wanted;
+            let reclaimed = u32::min(shrink, semaphore.permits() as u32);
+            if let Some(mut r) = semaphore.try_acquire(reclaimed as usize) {
+                r.disarm();
+                owed += shrink - reclaimed;
+            } else {
+                owed += shrink;
+            }
+        }
+        limit = wanted;
+        stats.record_task_max(limit);
         stats.record_current_tasks(semaphore.permits() as u32);
         stats.record_queue_depth(future_list.len() as u32);
         select! {
             mut s = semaphore.acquire(1) => {
                 s.disarm();
-                let t1 = worker_task(logger.new(o!("worker" => id)), semaphore.clone(), https.clone(), &requests, strategy, stats, id);
-                id = id.wrapping_add(1);
+                let vu = match idle.pop() {
+                    // Time spent waiting for a slot, e.g. after a ramp-down,
+                    // is ours rather than the server's, so the schedule moves
+                    // on by that much.
+                    Some((mut vu, since)) => {
+                        let waited = since.elapsed();
+                        vu.scheduled = vu.scheduled.map(|s| s + waited);
+                        vu
+                    }
+                    None => {
+                        id = id.wrapping_add(1);
+                        VirtualUser::new(id - 1, &run.sessions)
+                    }
+                };
+                let t1 = worker_task(logger.new(o!("worker" => vu.id)), run, stats, vu);
                 future_list.push(t1);
             },
             res = future_list.select_next_some() => {
-                debug!(logger, "Reaped batch {:?}, permits {}", res, semaphore.permits());
+                if owed > 0 {
+                    owed -= 1;
+                } else {
+                    semaphore.release(1);
+                }
+                debug!(logger, "Reaped batch {}, permits {}", res.id, semaphore.permits());
+                idle.push((res, Instant::now()));
+            },
+            _ = control.tick().fuse() => {}
+        }
+    }
+}
+
+// Iterations are launched on a fixed schedule regardless of how long earlier
+// ones take. The semaphore still bounds how many can be in flight; a slot that
+// comes due while it is exhausted is dropped rather than queued.
+async fn rate_loop(
+    logger: Logger,
+    run: &Run,
+    stats: Stats,
+    shutdown: Arc<Mutex<oneshot::Receiver<()>>>,
+) {
+    let semaphore = Semaphore::new(false, run.max_concurrency as usize);
+    let mut id: u64 = 0;
+    // Virtual users not currently running an iteration.
+    let mut idle: Vec<VirtualUser> = Vec::new();
+    let mut future_list = stream::FuturesUnordered::new();
+    let mut control = time::interval(CONTROL_INTERVAL);
+    let started = time::Instant::now();
+    let mut last_due: Option<time::Instant> = None;
+    let mut stats = stats;
+    stats.record_task_max(run.max_concurrency);
+    loop {
+        if shutdown.lock().await.try_recv().is_ok() {
+            info!(logger, "Received stop message");
+            break;
+        }
+        let target = match run.target(started.elapsed()) {
+            Some(t) => t,
+            None => {
+                info!(logger, "Run complete");
+                drain(&logger, &mut future_list, &shutdown).await;
+                break;
             }
+        };
+        let mut stats = stats.clone();
+        stats.record_stage(target.stage);
+        stats.record_current_tasks(semaphore.permits() as u32);
+        stats.record_queue_depth(future_list.len() as u32);
+        // A rate too small to schedule is treated like a rate of zero.
+        let due = if target.value > 0.0 && target.value.is_finite() {
+            Duration::try_from_secs_f64(1.0 / target.value)
+                .ok()
+                .and_then(|period| match last_due {
+                    Some(l) => l.checked_add(period),
+                    None => Some(time::Instant::now()),
+                })
+        } else {
+            None
+        };
+        // After a pause the schedule starts over rather than catching up on
+        // the slots it skipped.
+        if due.is_none() {
+            last_due = None;
+        }
+        let wait = async {
+            match due {
+                Some(due) => time::sleep_until(due).await,
+                None => futures::future::pending().await,
+            }
+        };
+        select! {
+            _ = wait.fuse() => {
+                last_due = due;
+                if due.map(|d| d.elapsed() > LATE_THRESHOLD).unwrap_or(false) {
+                    stats.record_late();
+                }
+                match semaphore.try_acquire(1) {
+                    Some(mut s) => {
+                        s.disarm();
+                        let mut vu = idle.pop().unwrap_or_else(|| {
+                            id = id.wrapping_add(1);
+                            VirtualUser::new(id - 1, &run.sessions)
+                        });
+                        vu.scheduled = due.map(time::Instant::into_std);
+                        let t1 = worker_task(logger.new(o!("worker" => vu.id)), run, stats, vu);
+                        future_list.push(t1);
+                    }
+                    None => stats.record_dropped(),
+                }
+            },
+            res = future_list.select_next_some() => {
+                semaphore.release(1);
+                debug!(logger, "Reaped batch {}, permits {}", res.id, semaphore.permits());
+                idle.push(res);
+            },
+            _ = control.tick().fuse() => {}
         }
     }
-    drop(future_list);
-    info!(logger, "Task Scheduler shutting down");
-    Ok(())
+}
+
+struct Response {
+    status: u16,
+    headers: hyper::HeaderMap,
+    headers_at: Instant,
+    body: Option<Vec<u8>>,
+    bytes_sent: u64,
+    bytes_received: u64,
 }
 
 async fn execute_one_request(
     client: &Client<ClientConnector, hyper::Body>,
     request: &messages::RequestSpec,
-) -> Result<u16> {
+    template: &RequestTemplate,
+    scope: &mut Scope<'_>,
+    mut cookies: Option<&mut CookieJar>,
+    keep_body: bool,
+) -> Result<Response> {
     let url = if let Some(ref field) = request.random_querystring {
         let uuid = uuid::Uuid::new_v4();
-        let mut url: Url = request.url.parse()?;
+        let mut url: Url = template.url.render(scope).parse()?;
         let query = if let Some(q) = url.query() {
             format!("{}&{}={}", q, field, uuid)
         } else {
@@ -248,7 +723,7 @@ async fn execute_one_request(
         url.set_query(Some(&query));
         url
     } else {
-        request.url.parse::<Url>()?
+        template.url.render(scope).parse::<Url>()?
     };
 
     let mut req = hyper::Request::builder()
@@ -256,85 +731,293 @@ async fn execute_one_request(
         .version(request.version.into())
         .method::<hyper::Method>(request.method.into());
 
-    for (k, v) in request.headers.iter() {
-        req = req.header(k, v);
+    for (k, v) in template.headers.iter() {
+        req = req.header(k, v.render(scope));
     }
     if let Some(ref header) = request.random_header {
         let uuid = uuid::Uuid::new_v4();
         req = req.header(header, uuid.to_string());
     }
-    let r = if let Some(ref b) = request.body {
-        req.body(hyper::Body::from(b.to_string())).unwrap()
+    if let Some(cookie) = cookies.as_ref().and_then(|jar| jar.header_for(&url)) {
+        req = req.header(hyper::header::COOKIE, cookie);
+    }
+    let body = template.body.as_ref().map(|b| b.render(scope));
+    let bytes_sent = body.as_ref().map(|b| b.len() as u64).unwrap_or(0);
+    let r = if let Some(b) = body {
+        req.body(hyper::Body::from(b))?
     } else {
-        req.body(hyper::Body::empty()).unwrap()
+        req.body(hyper::Body::empty())?
     };
     let res = client.request(r).await?;
-    Ok(res.status().as_u16())
+    let headers_at = Instant::now();
+    let status = res.status().as_u16();
+    let (parts, mut body) = res.into_parts();
+    if let Some(jar) = cookies.as_mut() {
+        jar.store(&url, &parts.headers);
+    }
+    // Drain the body so the download is timed and the connection can go back
+    // to the pool.
+    let mut kept = if keep_body { Some(Vec::new()) } else { None };
+    let mut bytes_received = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        bytes_received += chunk.len() as u64;
+        if let Some(ref mut kept) = kept {
+            kept.extend_from_slice(&chunk);
+        }
+    }
+    Ok(Response {
+        status,
+        headers: parts.headers,
+        headers_at,
+        body: kept,
+        bytes_sent,
+        bytes_received,
+    })
 }
 
-async fn worker_task(
-    logger: Logger,
-    semaphore: Arc<Semaphore>,
-    connector: ClientConnector,
-    requests: &[messages::RequestSpec],
-    strategy: messages::AttackStrategy,
-    mut stats: Stats,
-    id: u64,
-) -> u64 {
-    let http1_client: Client<_, hyper::Body> = Client::builder().build(connector.clone());
-    let http2_client: Client<_, hyper::Body> = Client::builder().http2_only(true).build(connector);
-    match strategy {
-        messages::AttackStrategy::Random => {
-            let req = if let Some(r) = requests.choose(&mut rand::thread_rng()) {
-                r
-            } else {
-                error!(logger, "Failed to randomly choose request");
-                return id;
+#[derive(Debug, PartialEq, Eq)]
+enum StepResult {
+    Passed,
+    Failed,
+    // Not sent because a feeder ran dry.
+    Skipped,
+}
+
+// A request fails when it errors, comes back with a 4xx/5xx, fails a check or
+// is missing a value it was meant to extract.
+async fn execute_and_record(
+    logger: &Logger,
+    run: &Run,
+    index: usize,
+    stats: &mut Stats,
+    scope: &mut Scope<'_>,
+    cookies: Option<&mut CookieJar>,
+    scheduled: Option<Instant>,
+) -> StepResult {
+    let req = &run.requests[index];
+    let client = &run.clients[index];
+    let checks = &run.checks[index];
+    let extractors = &run.extractors[index];
+    let template = &run.templates[index];
+    let keep_body = req.checks.iter().any(messages::Check::needs_body)
+        || req.extract.iter().any(messages::Extractor::needs_body);
+    if !scope.take_rows(&template.feeders) {
+        run.exhausted.store(true, Ordering::Release);
+        return StepResult::Skipped;
+    }
+    let started = Instant::now();
+    let request = execute_one_request(client, req, template, scope, cookies, keep_body);
+    let result = match req.request_timeout {
+        Some(timeout) => time::timeout(timeout, request).await.unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out").into())
+        }),
+        None => request.await,
+    };
+    let mut passed = true;
+    let status = match result {
+        Ok(r) => {
+            stats.record_response(
+                as_micros(r.headers_at - started),
+                r.bytes_sent,
+                r.bytes_received,
+            );
+            let outcome = checks::Outcome {
+                status: r.status,
+                headers: &r.headers,
+                body: r.body.as_deref(),
+                size: r.bytes_received,
+                latency: started.elapsed(),
             };
-            let client = if req.version == messages::HttpVersion::Http2 {
-                &http2_client
+            // A spec's own checks decide whether its response is good; without
+            // any, an error status fails the step.
+            if checks.is_empty() {
+                passed &= r.status < 400;
             } else {
-                &http1_client
-            };
-            let started = Instant::now();
-            let status = match execute_one_request(&client, &req).await {
-                Ok(s) => Some(s),
-                Err(e) => {
-                    error!(logger, "{}", e);
-                    None
+                let results: Vec<bool> = checks.iter().map(|c| c.passes(&outcome)).collect();
+                stats.record_checks(index, &results);
+                passed &= results.iter().all(|r| *r);
+            }
+            for e in extractors.iter() {
+                match e.extract(&outcome) {
+                    Some(value) => scope.set(&e.name, value),
+                    None => {
+                        debug!(logger, "Nothing to extract for {}", e.name);
+                        passed = false;
+                    }
                 }
-            };
-            let elapsed = started.elapsed();
-            stats.record(
-                status,
-                elapsed.as_millis().try_into().unwrap_or(u64::max_value()),
+            }
+            Some(r.status)
+        }
+        Err(e) => {
+            error!(logger, "{}", e);
+            let kind = errors::classify(&e);
+            if kind == messages::ErrorKind::Timeout {
+                stats.record_timeout(index, e.to_string(), as_micros(started.elapsed()));
+                return StepResult::Failed;
+            }
+            stats.record_error(kind, e.to_string());
+            passed = false;
+            None
+        }
+    };
+    let elapsed = started.elapsed();
+    // A request that went out behind schedule is charged for the wait too.
+    let behind = scheduled
+        .map(|s| started.saturating_duration_since(s))
+        .unwrap_or_default();
+    stats.record(
+        index,
+        status,
+        as_micros(elapsed),
+        as_micros(behind + elapsed),
+    );
+    if passed {
+        StepResult::Passed
+    } else {
+        StepResult::Failed
+    }
+}
+
+// State a virtual user carries from one iteration to the next. Without
+// virtual users every iteration starts out fresh.
+#[derive(Debug)]
+struct VirtualUser {
+    id: u64,
+    iterations: u64,
+    cookies: Option<CookieJar>,
+    vars: HashMap<String, String>,
+    // Set by think time and pacing; the next iteration waits until then.
+    next_at: Option<time::Instant>,
+    // When the next iteration should start under a rate or pacing schedule.
+    scheduled: Option<Instant>,
+}
+
+impl VirtualUser {
+    fn new(id: u64, sessions: &messages::Sessions) -> VirtualUser {
+        VirtualUser {
+            id,
+            iterations: 0,
+            cookies: if sessions.virtual_users {
+                Some(CookieJar::new())
+            } else {
+                None
+            },
+            vars: HashMap::new(),
+            next_at: None,
+            scheduled: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(jar) = self.cookies.as_mut() {
+            jar.clear();
+        }
+        self.vars.clear();
+    }
+}
+
+async fn worker_task(
+    logger: Logger,
+    run: &Run,
+    mut stats: Stats,
+    mut vu: VirtualUser,
+) -> VirtualUser {
+    let requests = &run.requests;
+    if let Some(n) = run.sessions.reset_every {
+        if vu.iterations > 0 && vu.iterations.is_multiple_of(n) {
+            debug!(
+                logger,
+                "Resetting session after {} iterations", vu.iterations
             );
+            vu.reset();
+        }
+    }
+    vu.iterations += 1;
+    // Only the first request of an iteration has a schedule to fall behind;
+    // later steps can't start before the one ahead of them finishes.
+    let mut scheduled = vu.scheduled.take();
+    // Waiting happens outside of any request, so it never counts towards
+    // latency, corrected or not.
+    if let Some(at) = vu.next_at.take() {
+        time::sleep_until(at).await;
+        scheduled = scheduled.map(|s| s.max(at.into_std()));
+    }
+    let started = time::Instant::now();
+    match run.strategy {
+        messages::AttackStrategy::Random
+        | messages::AttackStrategy::Weighted
+        | messages::AttackStrategy::RoundRobin => {
+            if !run.take_request() {
+                return vu;
+            }
+            let index = run.pick();
+            let mut scope = run.scope();
+            execute_and_record(
+                &logger,
+                run,
+                index,
+                &mut stats,
+                &mut scope,
+                vu.cookies.as_mut(),
+                scheduled.take(),
+            )
+            .await;
         }
         messages::AttackStrategy::InOrder => {
-            for req in requests {
-                let client = if req.version == messages::HttpVersion::Http2 {
-                    &http2_client
-                } else {
-                    &http1_client
-                };
-                let started = Instant::now();
-                let status = match execute_one_request(&client, &req).await {
-                    Ok(s) => Some(s),
-                    Err(e) => {
-                        error!(logger, "{}", e);
-                        None
-                    }
-                };
-                let elapsed = started.elapsed();
-                stats.record(
-                    status,
-                    elapsed.as_millis().try_into().unwrap_or(u64::max_value()),
-                );
+            for index in 0..requests.len() {
+                if index > 0 {
+                    run.step_think().await;
+                }
+                if !run.take_request() {
+                    break;
+                }
+                let mut scope = run.scope();
+                execute_and_record(
+                    &logger,
+                    run,
+                    index,
+                    &mut stats,
+                    &mut scope,
+                    vu.cookies.as_mut(),
+                    scheduled.take(),
+                )
+                .await;
+            }
+        }
+        messages::AttackStrategy::Scenario => {
+            let mut scope = run.scope().with_extracted(std::mem::take(&mut vu.vars));
+            for index in 0..requests.len() {
+                if index > 0 {
+                    run.step_think().await;
+                }
+                if !run.take_request() {
+                    break;
+                }
+                let cookies = vu.cookies.as_mut();
+                let result = execute_and_record(
+                    &logger,
+                    run,
+                    index,
+                    &mut stats,
+                    &mut scope,
+                    cookies,
+                    scheduled.take(),
+                )
+                .await;
+                if result == StepResult::Failed && run.abort_on_failure {
+                    stats.record_aborted(index);
+                    break;
+                }
+            }
+            if run.sessions.virtual_users {
+                vu.vars = scope.into_extracted();
             }
         }
     }
-    semaphore.release(1);
-    id
+    vu.next_at = run.next_iteration(started);
+    vu.scheduled = run.pacing.interval.map(|i| (started + i).into_std());
+    vu
 }
 
 async fn stats_executor(logger: Logger, stats: Stats, tx: watch::Sender<messages::Status>) {
@@ -343,17 +1026,37 @@ async fn stats_executor(logger: Logger, stats: Stats, tx: watch::Sender<messages
     pin_mut!(timeout);
     loop {
         let _ = timeout.tick().await;
+        stats.checkpoint();
         let s = stats.as_message();
         debug!(logger, "Sending stats => {:?}", &s);
         let _ = tx.send(s);
     }
 }
 
-pub fn run_forever(logger: Logger, addr: String) -> Result<()> {
+// Without a listener the worker exits when it loses the coordinator. With one
+// it keeps retrying, so it can still be inspected while the link is down.
+async fn connect_forever(logger: Logger, addr: String, state: State, health: Health) -> Result<()> {
+    loop {
+        health.connecting();
+        let res = run(logger.clone(), addr.clone(), state.clone(), health.clone()).await;
+        match &res {
+            Ok(_) => info!(logger, "Disconnected from coordinator"),
+            Err(e) => warn!(logger, "Lost coordinator: {}", e),
+        }
+        health.disconnected(res.err().map(|e| e.to_string()));
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+pub fn run_forever(logger: Logger, addr: String, listen: Option<String>) -> Result<()> {
+    let listen = listen
+        .map(|l| l.parse::<std::net::SocketAddr>())
+        .transpose()?;
     let rt = runtime::Builder::new_multi_thread().enable_all().build()?;
     let res = rt.block_on(async {
         let (c_tx, c_rx) = mpsc::channel(100);
         let stats = Stats::new();
+        let health = Health::new(&addr);
         let (stats_tx, stats_rx) = watch::channel(stats.as_message());
         let state = State::new(c_tx, stats_rx);
         tokio::spawn(stats_executor(
@@ -364,9 +1067,22 @@ pub fn run_forever(logger: Logger, addr: String) -> Result<()> {
         tokio::spawn(command_executor(
             logger.new(o!("task" => "executor")),
             stats.clone(),
+            health.clone(),
             c_rx,
         ));
-        tokio::spawn(run(logger.new(o!("task" => "receiver")), addr, state)).await
+        let receiver = logger.new(o!("task" => "receiver"));
+        match listen {
+            Some(listen) => {
+                tokio::spawn(worker_server::worker_server_task(
+                    logger.new(o!("task" => "listener")),
+                    listen,
+                    stats.clone(),
+                    health.clone(),
+                ));
+                tokio::spawn(connect_forever(receiver, addr, state, health)).await
+            }
+            None => tokio::spawn(run(receiver, addr, state, health)).await,
+        }
     });
     res?
 }
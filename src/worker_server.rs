@@ -0,0 +1,243 @@
+use crate::{messages, metrics, stats::Stats};
+use serde::Serialize;
+use slog::{info, Logger};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use warp::{self, Filter, Reply};
+
+use anyhow::Result as TaskResult;
+
+// What a worker knows about itself beyond its Stats: the state of its link to
+// the coordinator and the last command it was given. Served by the optional
+// worker listener so a worker can be looked at without going through the
+// coordinator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Link {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Clone)]
+struct LastCommand {
+    kind: &'static str,
+    run_id: Option<String>,
+    received: SystemTime,
+}
+
+#[derive(Debug)]
+struct HealthInner {
+    coordinator: String,
+    link: Link,
+    since: SystemTime,
+    connects: u32,
+    last_error: Option<String>,
+    command: Option<LastCommand>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Health {
+    inner: Arc<RwLock<HealthInner>>,
+    started: SystemTime,
+    started_at: Instant,
+}
+
+impl Health {
+    pub fn new(coordinator: &str) -> Health {
+        let now = SystemTime::now();
+        Health {
+            inner: Arc::new(RwLock::new(HealthInner {
+                coordinator: coordinator.to_string(),
+                link: Link::Connecting,
+                since: now,
+                connects: 0,
+                last_error: None,
+                command: None,
+            })),
+            started: now,
+            started_at: Instant::now(),
+        }
+    }
+
+    fn set_link(&self, link: Link) {
+        let mut inner = self.inner.write().unwrap();
+        if inner.link != link {
+            inner.link = link;
+            inner.since = SystemTime::now();
+        }
+    }
+
+    pub fn connecting(&self) {
+        self.set_link(Link::Connecting);
+    }
+
+    pub fn connected(&self) {
+        self.set_link(Link::Connected);
+        self.inner.write().unwrap().connects += 1;
+    }
+
+    pub fn disconnected(&self, error: Option<String>) {
+        self.set_link(Link::Disconnected);
+        self.inner.write().unwrap().last_error = error;
+    }
+
+    pub fn command(&self, cmd: &messages::Command) {
+        let (kind, run_id) = match cmd {
+            messages::Command::Start(spec) => ("start", Some(spec.run_id.clone())),
+            messages::Command::Stop => ("stop", None),
+            messages::Command::Reset => ("reset", None),
+        };
+        self.inner.write().unwrap().command = Some(LastCommand {
+            kind,
+            run_id,
+            received: SystemTime::now(),
+        });
+    }
+
+    pub fn link(&self) -> Link {
+        self.inner.read().unwrap().link
+    }
+
+    pub fn connects(&self) -> u32 {
+        self.inner.read().unwrap().connects
+    }
+
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+}
+
+fn epoch_millis(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Serialize)]
+struct CoordinatorResponse {
+    url: String,
+    state: Link,
+    since: u128,
+    connects: u32,
+    last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CommandResponse {
+    kind: &'static str,
+    run_id: Option<String>,
+    received: u128,
+}
+
+#[derive(Debug, Serialize)]
+struct ProcessResponse {
+    pid: u32,
+    hostname: Option<String>,
+    version: &'static str,
+    started: u128,
+    uptime_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct StatusResponse {
+    state: messages::WorkerState,
+    run_id: Option<String>,
+    tasks: u32,
+    coordinator: CoordinatorResponse,
+    command: Option<CommandResponse>,
+    process: ProcessResponse,
+}
+
+#[derive(Clone)]
+struct State {
+    stats: Stats,
+    health: Health,
+}
+
+impl State {
+    fn status(&self) -> messages::Status {
+        let mut status = self.stats.as_message();
+        status.hostname = crate::worker::hostname();
+        status
+    }
+}
+
+async fn get_stats(state: State) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&state.status()))
+}
+
+async fn get_metrics(state: State) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::with_header(
+        metrics::render_worker(state.status(), &state.health),
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+async fn get_status(state: State) -> Result<impl Reply, Infallible> {
+    let status = state.status();
+    let health = &state.health;
+    let inner = health.inner.read().unwrap();
+    let r = StatusResponse {
+        state: status.state,
+        run_id: status.run_id,
+        tasks: status.tasks,
+        coordinator: CoordinatorResponse {
+            url: inner.coordinator.clone(),
+            state: inner.link,
+            since: epoch_millis(inner.since),
+            connects: inner.connects,
+            last_error: inner.last_error.clone(),
+        },
+        command: inner.command.as_ref().map(|c| CommandResponse {
+            kind: c.kind,
+            run_id: c.run_id.clone(),
+            received: epoch_millis(c.received),
+        }),
+        process: ProcessResponse {
+            pid: std::process::id(),
+            hostname: status.hostname,
+            version: env!("CARGO_PKG_VERSION"),
+            started: epoch_millis(health.started),
+            uptime_secs: health.started_at.elapsed().as_secs(),
+        },
+    };
+    Ok(warp::reply::json(&r))
+}
+
+fn with_state(state: State) -> impl Filter<Extract = (State,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+pub async fn worker_server_task(
+    logger: Logger,
+    addr: SocketAddr,
+    stats: Stats,
+    health: Health,
+) -> TaskResult<()> {
+    let state = State { stats, health };
+
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(get_metrics);
+
+    let stats = warp::path!("stats")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(get_stats);
+
+    let status = warp::path!("status")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(get_status);
+
+    let routes = metrics.or(stats).or(status);
+    info!(logger, "Starting worker listener at {}", addr);
+    warp::serve(routes).run(addr).await;
+    Ok(())
+}
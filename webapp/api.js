@@ -3,17 +3,32 @@ import m from 'mithril';
 const StatsApi = {
     fetching: false,
     data: [],
+    cluster: undefined,
+    update: function(resp) {
+        let items = resp.items;
+        items.sort((first, second) => first.id - second.id);
+        StatsApi.data = items;
+        StatsApi.cluster = resp.cluster;
+    },
     fetch: function() {
         StatsApi.fetching = true;
         m.request({
             method: "GET",
             url: "/stats",
         }).then((resp) => {
-            let items = resp.items;
-            items.sort((first, second) => first.id - second.id);
-            StatsApi.data = items;
+            StatsApi.update(resp);
             StatsApi.fetching = false;
         });
+    },
+    // Pushes every update as it happens; returns the EventSource so the
+    // caller can close it.
+    follow: function() {
+        let source = new EventSource("/stats/stream");
+        source.addEventListener("stats", (e) => {
+            StatsApi.update(JSON.parse(e.data));
+            m.redraw();
+        });
+        return source;
     }
 };
 
@@ -6,6 +6,9 @@ import SpecEditor from './SpecEditor';
 const possibleStrategies = [
     {id: "Random", display: "Random"},
     {id: "InOrder", display: "In Order"},
+    {id: "Scenario", display: "Scenario"},
+    {id: "Weighted", display: "Weighted"},
+    {id: "RoundRobin", display: "Round Robin"},
 ];
 
 function indexOfStrategy(id) {
@@ -25,6 +25,10 @@ function formatMillis(ms) {
     return res;
 }
 
+function formatLatency(us) {
+    return ((us || 0) / 1000).toFixed(2);
+}
+
 const Meter = {
 
     view: (vnode) => {
@@ -53,12 +57,12 @@ function makeWorkerRow(stats) {
                 <td><abbr title={stats.socket}>{stats.hostname}</abbr></td>
                 <td>{stats.state}</td>
                 <td>{s.elapsed ? formatMillis(s.elapsed) : ""}</td>
-                <td><Gauge value={s.min}/></td>
-                <td><Gauge value={s.mean.toFixed(1)}/></td>
-                <td><Gauge value={s.stdev.toFixed(1)}/></td>
-                <td><Gauge value={s.median}/></td>
-                <td><Gauge value={s.p90}/></td>
-                <td><Gauge value={s.max}/></td>
+                <td><Gauge value={formatLatency(s.min)}/></td>
+                <td><Gauge value={formatLatency(s.mean)}/></td>
+                <td><Gauge value={formatLatency(s.stdev)}/></td>
+                <td><Gauge value={formatLatency(s.median)}/></td>
+                <td><Gauge value={formatLatency(s.p90)}/></td>
+                <td><Gauge value={formatLatency(s.max)}/></td>
                 <td><Meter rate={s.rate_count} count={s.count}/></td>
                 <td><Meter rate={s.rate_1xx} count={s.count_1xx}/></td>
                 <td className="bg-green">
@@ -80,80 +84,23 @@ function makeWorkerRow(stats) {
             </tr>)
 }
 
-function makeSummaryRow(stats) {
-    let initial = {
-        elapsed: 0,
-        min: undefined,
+function makeSummaryRow(cluster) {
+    let data = cluster || {
+        min: 0,
         mean: 0,
         stdev: 0,
         median: 0,
         p90: 0,
         max: 0,
-        count: 0,
-        rate_count: 0,
-        count_1xx: 0,
-        rate_1xx: 0,
-        count_2xx: 0,
-        rate_2xx: 0,
-        count_3xx: 0,
-        rate_3xx: 0,
-        count_4xx: 0,
-        rate_4xx: 0,
-        count_5xx: 0,
-        rate_5xx: 0,
-        count_fail: 0,
-        rate_fail: 0,
-    };
-    let reducer = (acc, s) => {
-        if (!s) {
-            return acc;
-        }
-
-        if (acc.min === undefined || s.min < acc.min) {
-            acc.min = s.min;
-        }
-        if (s.max > acc.max) {
-            acc.max = s.max;
-        }
-        acc.count += s.count;
-        acc.rate_count += s.rate_count;
-        acc.count_1xx += s.count_1xx;
-        acc.rate_1xx += s.rate_1xx;
-        acc.count_2xx += s.count_2xx;
-        acc.rate_2xx += s.rate_2xx;
-        acc.count_3xx += s.count_3xx;
-        acc.rate_3xx += s.rate_3xx;
-        acc.count_4xx += s.count_4xx;
-        acc.rate_4xx += s.rate_4xx;
-        acc.count_5xx += s.count_5xx;
-        acc.rate_5xx += s.rate_5xx;
-        acc.count_fail += s.count_fail;
-        acc.rate_fail += s.rate_fail;
-        acc.mean += s.mean;
-        acc.stdev += s.stdev;
-        acc.median += s.median;
-        acc.p90 += s.p90;
-        return acc;
     };
-    let data = stats
-        .filter(s => s.state !== "Disconnected")
-        .map(s => s.latest || undefined)
-        .reduce(reducer, initial);
-    let datapoints = stats.filter(s => s.state !== "Disconnected").length;
-    if (datapoints > 0) {
-        data['mean'] = data['mean'] / datapoints;
-        data['stdev'] = data['stdev'] / datapoints;
-        data['median'] = data['median'] / datapoints;
-        data['p90'] = data['p90'] / datapoints;
-    }
     return (<tr className="footer-row">
                 <td colspan="4"></td>
-                <td><Gauge value={data.min}/></td>
-                <td><Gauge value={data.mean.toFixed(1)}/></td>
-                <td><Gauge value={data.stdev.toFixed(1)}/></td>
-                <td><Gauge value={data.median.toFixed(1)}/></td>
-                <td><Gauge value={data.p90.toFixed(1)}/></td>
-                <td><Gauge value={data.max}/></td>
+                <td><Gauge value={formatLatency(data.min)}/></td>
+                <td><Gauge value={formatLatency(data.mean)}/></td>
+                <td><Gauge value={formatLatency(data.stdev)}/></td>
+                <td><Gauge value={formatLatency(data.median)}/></td>
+                <td><Gauge value={formatLatency(data.p90)}/></td>
+                <td><Gauge value={formatLatency(data.max)}/></td>
                 <td><Meter rate={data.rate_count} count={data.count}/></td>
                 <td><Meter rate={data.rate_1xx} count={data.count_1xx}/></td>
                 <td>
@@ -177,12 +124,20 @@ function makeSummaryRow(stats) {
 
 function WorkerTable(initial) {
     var interval = undefined;
+    var source = undefined;
     return {
         oninit: (vnode) => {
-            StatsApi.fetch();
-            interval = setInterval(StatsApi.fetch, 2000);
+            if (window.EventSource) {
+                source = StatsApi.follow();
+            } else {
+                StatsApi.fetch();
+                interval = setInterval(StatsApi.fetch, 2000);
+            }
         },
         onremove: (vnode) => {
+            if (source) {
+                source.close();
+            }
             if (interval) {
                 clearInterval(interval);
             }
@@ -220,7 +175,7 @@ function WorkerTable(initial) {
                             { StatsApi.data.map(makeWorkerRow) }
                         </tbody>
                         <tfoot>
-                            { makeSummaryRow(StatsApi.data) }
+                            { makeSummaryRow(StatsApi.cluster) }
                         </tfoot>
                     </table>);
         }